@@ -0,0 +1,221 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::result::AppResult;
+
+/// Folder inside the out dir where the previous copies of overwritten files are kept.
+pub const VERSIONS_DIR: &str = ".nubesync-versions";
+
+const SUFFIX_FORMAT: &str = "%Y-%m-%dT%H-%M-%S-%3f";
+
+/// Retention rules of the archived copies. Every copy is kept if none is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Versioning {
+    /// Keep the newest copies of each file.
+    #[serde(default)]
+    pub keep_last: Option<usize>,
+    /// Keep the newest copy of each day, for these days.
+    #[serde(default)]
+    pub keep_daily_days: Option<u64>,
+}
+
+/// Archive of previous file copies, mirroring the out dir structure.
+/// Copies are named like `<file name>.<timestamp>`.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    out_dir: PathBuf,
+    root: PathBuf,
+    retention: Versioning,
+}
+
+impl Archive {
+    pub fn new(out_dir: &Path, retention: Versioning) -> Self {
+        Self {
+            out_dir: out_dir.to_path_buf(),
+            root: out_dir.join(VERSIONS_DIR),
+            retention,
+        }
+    }
+
+    /// Move `path`, which must be inside the out dir, to the archive and apply the retention rules.
+    pub fn archive(&self, path: &Path) -> AppResult<()> {
+        let relative = path.strip_prefix(&self.out_dir)?;
+        let file_name = relative
+            .file_name()
+            .ok_or_else(|| format!("invalid path to archive: {}", path.display()))?
+            .to_string_lossy()
+            .to_string();
+        let dir = match relative.parent() {
+            Some(parent) => self.root.join(parent),
+            None => self.root.clone(),
+        };
+        std::fs::create_dir_all(&dir)?;
+
+        let suffix = Utc::now().format(SUFFIX_FORMAT);
+        let destination = dir.join(format!("{}.{}", file_name, suffix));
+        info!(path = %destination.display(), "archiving previous version");
+        std::fs::rename(path, destination)?;
+
+        self.apply_retention(&dir, &file_name)
+    }
+
+    /// Erase the copies of `file_name` inside `dir` not covered by the retention rules.
+    fn apply_retention(&self, dir: &Path, file_name: &str) -> AppResult<()> {
+        if self.retention.keep_last.is_none() && self.retention.keep_daily_days.is_none() {
+            return Ok(());
+        }
+
+        let prefix = format!("{}.", file_name);
+        let mut versions = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(suffix) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+
+            if let Ok(created) = NaiveDateTime::parse_from_str(suffix, SUFFIX_FORMAT) {
+                versions.push((created, entry.path()));
+            }
+        }
+        versions.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+
+        let mut keep = HashSet::new();
+        if let Some(keep_last) = self.retention.keep_last {
+            keep.extend(versions.iter().take(keep_last).map(|(_, path)| path));
+        }
+
+        if let Some(days) = self.retention.keep_daily_days {
+            let limit = Utc::now().naive_utc() - Duration::days(days as i64);
+            let mut seen_days = HashSet::new();
+            for (created, path) in &versions {
+                if *created >= limit && seen_days.insert(created.date()) {
+                    keep.insert(path);
+                }
+            }
+        }
+
+        for (_, path) in &versions {
+            if !keep.contains(path) {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, NaiveTime, TimeZone};
+
+    use super::*;
+
+    /// Create a copy of `a.txt` archived at `created`.
+    fn add_copy(archive: &Path, created: DateTime<Utc>) -> String {
+        let name = format!("a.txt.{}", created.format(SUFFIX_FORMAT));
+        std::fs::write(archive.join(&name), "old").unwrap();
+        name
+    }
+
+    fn copies(archive: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(archive)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn archive_file(
+        retention: Versioning,
+        copies_at: &[DateTime<Utc>],
+    ) -> (Vec<String>, Vec<String>) {
+        let out_dir = tempfile::tempdir().unwrap();
+        let archive_dir = out_dir.path().join(VERSIONS_DIR);
+        std::fs::create_dir_all(&archive_dir).unwrap();
+        let old: Vec<_> = copies_at
+            .iter()
+            .map(|created| add_copy(&archive_dir, *created))
+            .collect();
+
+        let file = out_dir.path().join("a.txt");
+        std::fs::write(&file, "current").unwrap();
+        Archive::new(out_dir.path(), retention)
+            .archive(&file)
+            .unwrap();
+        assert!(!file.exists());
+
+        (old, copies(&archive_dir))
+    }
+
+    #[test]
+    fn keep_every_copy_without_rules() {
+        let now = Utc::now();
+        let (old, kept) = archive_file(Versioning::default(), &[now - Duration::days(400)]);
+
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(&old[0]));
+    }
+
+    #[test]
+    fn keep_last_copies() {
+        let now = Utc::now();
+        let retention = Versioning {
+            keep_last: Some(2),
+            keep_daily_days: None,
+        };
+        let (old, kept) = archive_file(
+            retention,
+            &[
+                now - Duration::days(3),
+                now - Duration::days(2),
+                now - Duration::days(1),
+            ],
+        );
+
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(&old[2]));
+        assert!(!kept.contains(&old[0]) && !kept.contains(&old[1]));
+    }
+
+    #[test]
+    fn keep_newest_copy_of_each_day() {
+        let today = Utc::now().date_naive();
+        let at = |days: i64, hour: u32| {
+            let date = today - Duration::days(days);
+            Utc.from_utc_datetime(&date.and_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap()))
+        };
+        let retention = Versioning {
+            keep_last: None,
+            keep_daily_days: Some(2),
+        };
+        let (old, kept) = archive_file(retention, &[at(5, 12), at(1, 11), at(1, 12)]);
+
+        // the new copy and the newest one of yesterday
+        assert_eq!(kept.len(), 2);
+        assert!(kept.contains(&old[2]));
+    }
+
+    #[test]
+    fn rules_add_up() {
+        let now = Utc::now();
+        let retention = Versioning {
+            keep_last: Some(3),
+            keep_daily_days: Some(1),
+        };
+        let (old, kept) = archive_file(
+            retention,
+            &[now - Duration::days(30), now - Duration::days(20)],
+        );
+
+        assert_eq!(kept.len(), 3);
+        assert!(kept.contains(&old[0]) && kept.contains(&old[1]));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+/// Transfer rate in bytes per second, written like `2MiB/s` or `500KB/s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rate(u64);
+
+impl Rate {
+    pub fn bytes_per_second(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim();
+        let value = value.strip_suffix("/s").unwrap_or(value).trim();
+        let split_at = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split_at);
+
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid bandwidth value: {}", s))?;
+        let multiplier = match unit.trim() {
+            "" | "B" => 1,
+            "K" | "KB" => 1_000,
+            "KiB" => 1 << 10,
+            "M" | "MB" => 1_000_000,
+            "MiB" => 1 << 20,
+            "G" | "GB" => 1_000_000_000,
+            "GiB" => 1 << 30,
+            unit => return Err(format!("unknown bandwidth unit: {}", unit)),
+        };
+
+        let rate = (number * multiplier as f64) as u64;
+        if rate == 0 {
+            return Err(format!("bandwidth must be greater than zero: {}", s));
+        }
+
+        Ok(Rate(rate))
+    }
+}
+
+impl TryFrom<String> for Rate {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}B/s", self.0)
+    }
+}
+
+impl From<Rate> for String {
+    fn from(rate: Rate) -> Self {
+        rate.to_string()
+    }
+}
+
+/// Time of day window, written like `08:00-18:00`. Windows where the end is
+/// before the start wrap around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            return self.start <= time && time < self.end;
+        }
+
+        time >= self.start || time < self.end
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time window: {}", s))?;
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time in window: {}", s))
+        };
+
+        Ok(TimeWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        format!(
+            "{}-{}",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        )
+    }
+}
+
+/// Rates applied during specific hours of the day, overriding the default limit.
+pub type BandwidthSchedule = BTreeMap<TimeWindow, Rate>;
+
+/// Token bucket shared by every download of the service. Clones share the same bucket.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimiter {
+    limit: Option<Rate>,
+    schedule: BandwidthSchedule,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limit: Option<Rate>, schedule: BandwidthSchedule) -> Self {
+        Self {
+            limit,
+            schedule,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Rate in effect right now, `None` means unlimited.
+    pub fn current_rate(&self) -> Option<Rate> {
+        self.rate_at(Local::now().time())
+    }
+
+    /// Rate in effect at `time` of the day.
+    fn rate_at(&self, time: NaiveTime) -> Option<Rate> {
+        self.schedule
+            .iter()
+            .find(|(window, _)| window.contains(time))
+            .map(|(_, rate)| *rate)
+            .or(self.limit)
+    }
+
+    /// Take `bytes` tokens from the bucket, waiting until the transfer fits in the current rate.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.current_rate() else {
+            return;
+        };
+
+        let wait = {
+            let rate = rate.bytes_per_second() as f64;
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!("100".parse::<Rate>().unwrap().bytes_per_second(), 100);
+        assert_eq!(
+            "500KB/s".parse::<Rate>().unwrap().bytes_per_second(),
+            500_000
+        );
+        assert_eq!(
+            "2MiB/s".parse::<Rate>().unwrap().bytes_per_second(),
+            2 << 20
+        );
+        assert_eq!(
+            "1.5M".parse::<Rate>().unwrap().bytes_per_second(),
+            1_500_000
+        );
+        assert_eq!(
+            " 1 GiB /s ".parse::<Rate>().unwrap().bytes_per_second(),
+            1 << 30
+        );
+    }
+
+    #[test]
+    fn reject_invalid_rates() {
+        assert!("".parse::<Rate>().is_err());
+        assert!("fast".parse::<Rate>().is_err());
+        assert!("10TB/s".parse::<Rate>().is_err());
+        assert!("0KB/s".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn rate_round_trip() {
+        let rate: Rate = "2KiB/s".parse().unwrap();
+        assert_eq!(String::from(rate), "2048B/s");
+        assert_eq!(String::from(rate).parse::<Rate>().unwrap(), rate);
+    }
+
+    #[test]
+    fn parse_windows() {
+        let window: TimeWindow = "08:00-18:30".parse().unwrap();
+        assert_eq!(String::from(window), "08:00-18:30");
+
+        assert!("08:00".parse::<TimeWindow>().is_err());
+        assert!("8am-6pm".parse::<TimeWindow>().is_err());
+        assert!("08:00-25:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn window_within_day() {
+        let window: TimeWindow = "08:00-18:00".parse().unwrap();
+        assert!(window.contains(time("08:00")));
+        assert!(window.contains(time("12:00")));
+        assert!(!window.contains(time("18:00")));
+        assert!(!window.contains(time("07:59")));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window: TimeWindow = "22:00-06:00".parse().unwrap();
+        assert!(window.contains(time("22:00")));
+        assert!(window.contains(time("23:59")));
+        assert!(window.contains(time("00:00")));
+        assert!(window.contains(time("05:59")));
+        assert!(!window.contains(time("06:00")));
+        assert!(!window.contains(time("12:00")));
+    }
+
+    #[test]
+    fn schedule_overrides_limit() {
+        let schedule: BandwidthSchedule = toml::from_str(
+            r#"
+            "08:00-18:00" = "1MB/s"
+            "22:00-02:00" = "10MB/s"
+            "#,
+        )
+        .unwrap();
+        let limiter = BandwidthLimiter::new(Some("100KB/s".parse().unwrap()), schedule);
+
+        let rate = |at| {
+            limiter
+                .rate_at(time(at))
+                .map(|rate| rate.bytes_per_second())
+        };
+        assert_eq!(rate("09:00"), Some(1_000_000));
+        assert_eq!(rate("23:00"), Some(10_000_000));
+        assert_eq!(rate("01:00"), Some(10_000_000));
+        assert_eq!(rate("20:00"), Some(100_000));
+    }
+
+    #[test]
+    fn unlimited_outside_schedule() {
+        let mut schedule = BandwidthSchedule::new();
+        schedule.insert("22:00-06:00".parse().unwrap(), "1MB/s".parse().unwrap());
+        let limiter = BandwidthLimiter::new(None, schedule);
+
+        assert!(limiter.rate_at(time("03:00")).is_some());
+        assert!(limiter.rate_at(time("12:00")).is_none());
+    }
+}
@@ -0,0 +1,215 @@
+use std::{fmt::Display, fs::File, io::Read, path::Path, str::FromStr};
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+use crate::result::AppResult;
+
+/// Hash algorithms used by Nextcloud checksums.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha1,
+    Md5,
+    Adler32,
+}
+
+/// Checksum formatted like Nextcloud does (`SHA1:<hex>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+impl Checksum {
+    /// Parse a space separated list of checksums, like the `oc:checksum` property.
+    /// Unknown algorithms are ignored, the strongest one comes first.
+    pub fn parse_list(list: &str) -> Vec<Checksum> {
+        let mut checksums: Vec<Checksum> = list
+            .split_whitespace()
+            .filter_map(|checksum| checksum.parse().ok())
+            .collect();
+        checksums.sort_by_key(|checksum| checksum.algorithm as u8);
+
+        checksums
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, value) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("invalid checksum: {}", s))?;
+        let algorithm = match algorithm.to_ascii_uppercase().as_str() {
+            "SHA1" => ChecksumAlgorithm::Sha1,
+            "MD5" => ChecksumAlgorithm::Md5,
+            "ADLER32" => ChecksumAlgorithm::Adler32,
+            _ => return Err(format!("unsupported checksum algorithm: {}", algorithm)),
+        };
+
+        Ok(Checksum {
+            algorithm,
+            value: value.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let algorithm = match self.algorithm {
+            ChecksumAlgorithm::Sha1 => "SHA1",
+            ChecksumAlgorithm::Md5 => "MD5",
+            ChecksumAlgorithm::Adler32 => "ADLER32",
+        };
+
+        write!(f, "{}:{}", algorithm, self.value)
+    }
+}
+
+/// Incremental hash of a file content with every supported algorithm.
+#[derive(Debug, Clone)]
+pub struct ContentHasher {
+    sha1: Sha1,
+    md5: Md5,
+    adler32: adler::Adler32,
+}
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        Self {
+            sha1: Sha1::new(),
+            md5: Md5::new(),
+            adler32: adler::Adler32::new(),
+        }
+    }
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha1.update(data);
+        self.md5.update(data);
+        self.adler32.write_slice(data);
+    }
+
+    pub fn finish(self) -> ContentDigest {
+        ContentDigest {
+            sha1: format!("{:x}", self.sha1.finalize()),
+            md5: format!("{:x}", self.md5.finalize()),
+            adler32: format!("{:08x}", self.adler32.checksum()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentDigest {
+    sha1: String,
+    md5: String,
+    adler32: String,
+}
+
+impl ContentDigest {
+    pub fn checksum(&self, algorithm: ChecksumAlgorithm) -> Checksum {
+        let value = match algorithm {
+            ChecksumAlgorithm::Sha1 => &self.sha1,
+            ChecksumAlgorithm::Md5 => &self.md5,
+            ChecksumAlgorithm::Adler32 => &self.adler32,
+        };
+
+        Checksum {
+            algorithm,
+            value: value.clone(),
+        }
+    }
+
+    pub fn matches(&self, expected: &Checksum) -> bool {
+        self.checksum(expected.algorithm) == *expected
+    }
+}
+
+/// Compute the digest of a local file.
+pub fn file_digest(path: &Path) -> AppResult<ContentDigest> {
+    let mut file = File::open(path)?;
+    let mut hasher = ContentHasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_checksum() {
+        let checksum: Checksum = "SHA1:AbCdEf".parse().unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Sha1);
+        assert_eq!(checksum.value, "abcdef");
+        assert_eq!(checksum.to_string(), "SHA1:abcdef");
+
+        let checksum: Checksum = "adler32:0a1b2c3d".parse().unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Adler32);
+    }
+
+    #[test]
+    fn reject_invalid_checksums() {
+        assert!("".parse::<Checksum>().is_err());
+        assert!("d41d8cd98f00b204e9800998ecf8427e"
+            .parse::<Checksum>()
+            .is_err());
+        assert!("SHA256:abc".parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn parse_list_strongest_first() {
+        let checksums = Checksum::parse_list("ADLER32:0001 SHA256:ffff MD5:0002 SHA1:0003");
+        let algorithms: Vec<_> = checksums
+            .iter()
+            .map(|checksum| checksum.algorithm)
+            .collect();
+        assert_eq!(
+            algorithms,
+            [
+                ChecksumAlgorithm::Sha1,
+                ChecksumAlgorithm::Md5,
+                ChecksumAlgorithm::Adler32
+            ]
+        );
+        assert_eq!(checksums[0].value, "0003");
+
+        assert!(Checksum::parse_list("").is_empty());
+        assert!(Checksum::parse_list("SHA256:ffff").is_empty());
+    }
+
+    #[test]
+    fn digest_matches_known_values() {
+        let mut hasher = ContentHasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let digest = hasher.finish();
+
+        let expected = [
+            "SHA1:2aae6c35c94fcfb415dbe95f408b9ce91ee846ed",
+            "MD5:5eb63bbbe01eeed093cb22bb8f5acdc3",
+            "ADLER32:1a0b045d",
+        ];
+        for expected in expected {
+            assert!(digest.matches(&expected.parse().unwrap()), "{}", expected);
+        }
+        assert!(!digest.matches(&"MD5:00".parse().unwrap()));
+    }
+}
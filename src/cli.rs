@@ -1,12 +1,70 @@
-use std::path::PathBuf;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use clap::Parser;
 
+use serde_json::{json, Value};
+use tracing::level_filters::LevelFilter;
+
+use crate::{
+    config::Config,
+    control::default_socket_location,
+    logging::{LogFormat, LogOptions},
+    progress::ProgressMode,
+    result::AppResult,
+    store::{self, DbBackend},
+    sync_service::{ClearOptions, SyncOptions, VerifyMode, WatchOptions},
+};
+
 #[derive(Debug, Parser)]
 #[command(version)]
 pub struct NubeSyncCommand {
     #[clap(subcommand)]
     pub cmd: SubCommand,
+
+    #[clap(flatten)]
+    pub log: LogArgs,
+}
+
+/// Logging options, accepted by every command.
+#[derive(Debug, Parser)]
+pub struct LogArgs {
+    /// Log more details, `-vv` for tracing. Ignored if `RUST_LOG` is set.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log only warnings, `-qq` only errors. Ignored if `RUST_LOG` is set.
+    #[clap(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Format of the log lines.
+    #[clap(long, value_enum, default_value_t, global = true)]
+    log_format: LogFormat,
+
+    /// Write the logs to this file instead of stderr, rotated daily keeping a week.
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+}
+
+impl LogArgs {
+    pub fn options(&self) -> LogOptions {
+        let level = match (self.verbose, self.quiet) {
+            (0, 0) => LevelFilter::INFO,
+            (1, _) => LevelFilter::DEBUG,
+            (_, 0) => LevelFilter::TRACE,
+            (_, 1) => LevelFilter::WARN,
+            _ => LevelFilter::ERROR,
+        };
+
+        LogOptions {
+            level,
+            format: self.log_format,
+            file: self.log_file.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -14,16 +72,36 @@ pub enum SubCommand {
     /// Sync files from the host server to the local machine.
     Sync(SyncSubCommand),
 
-    /// Clear the out directory if .sync file exist inside.
+    /// Keep running and sync files from the host server periodically.
+    Watch(WatchSubCommand),
+
+    /// Keep syncing every pair in the config, meant to run as a systemd service.
+    Daemon(DaemonSubCommand),
+
+    /// Query and control a running daemon.
+    Ctl(CtlSubCommand),
+
+    /// Check the synced files on disk and download again the missing or altered ones.
+    Verify(VerifySubCommand),
+
+    /// Remove the synced files of the out directory if .sync file exist inside.
     Clear(ClearSubCommand),
 
+    /// Manage the entries deleted on the server and moved to the local trash.
+    #[clap(subcommand)]
+    Trash(TrashSubCommand),
+
+    /// Manage the local version database.
+    #[clap(subcommand)]
+    Db(DbSubCommand),
+
     #[cfg(feature = "version_migration")]
     /// Migrate the old version of the database to the new one.
-    Migrate(SyncSubCommand),
+    Migrate(RemoteArgs),
 }
 
 #[derive(Debug, Parser)]
-pub struct SyncSubCommand {
+pub struct RemoteArgs {
     /// Remote location of the files in the host server.
     #[clap(value_parser)]
     remote_location: PathBuf,
@@ -35,16 +113,19 @@ pub struct SyncSubCommand {
     /// Location of the config file. If not set, try to load `./nube-sync.config.toml`
     #[clap(long)]
     config: Option<PathBuf>,
+
+    /// Wait for other nubesync runs on the same local db to finish instead of failing.
+    #[clap(long)]
+    wait: bool,
+
+    /// Don't show the progress of the downloads.
+    #[clap(long)]
+    no_progress: bool,
 }
 
-impl SyncSubCommand {
+impl RemoteArgs {
     pub fn remote_location(&self) -> String {
-        let mut str_location = self.remote_location.display().to_string();
-        if !str_location.ends_with('/') {
-            str_location.push('/');
-        }
-
-        str_location
+        normalize_remote_location(&self.remote_location)
     }
 
     pub fn out_dir(&self) -> Option<&PathBuf> {
@@ -52,15 +133,356 @@ impl SyncSubCommand {
     }
 
     pub fn config_location(&self) -> PathBuf {
-        self.config.clone().unwrap_or_else(|| {
-            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("nube-sync.config.toml")
-        })
+        self.config.clone().unwrap_or_else(default_config_location)
     }
+
+    pub fn wait(&self) -> bool {
+        self.wait
+    }
+
+    /// Bars in a terminal, periodic lines otherwise.
+    pub fn progress(&self) -> Option<ProgressMode> {
+        (!self.no_progress).then(ProgressMode::detect)
+    }
+}
+
+pub fn normalize_remote_location(remote_location: &Path) -> String {
+    let mut str_location = remote_location.display().to_string();
+    if !str_location.ends_with('/') {
+        str_location.push('/');
+    }
+
+    str_location
+}
+
+fn default_config_location() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("nube-sync.config.toml")
 }
 
 #[derive(Debug, Parser)]
-pub struct ClearSubCommand {
-    /// Delete all files in the out directory if finds a `.sync` file inside.
+pub struct SyncSubCommand {
+    #[clap(flatten)]
+    pub remote: RemoteArgs,
+
+    /// Check the synced files on disk before syncing, downloading again the altered ones.
+    #[clap(long)]
+    verify: bool,
+
+    /// With `--verify`, also compare the content checksum of each file.
+    #[clap(long, requires = "verify")]
+    checksum: bool,
+
+    /// Delete the entries removed on the server even if they exceed the deletion limits in config.
+    #[clap(long)]
+    force_delete: bool,
+
+    /// Sync the local db with this host and remote location, even if it was synced with others.
+    #[clap(long)]
+    rebind: bool,
+
+    /// Write a JSON report of each sync in this file.
+    #[clap(long)]
+    report: Option<PathBuf>,
+}
+
+impl SyncSubCommand {
+    pub fn options(&self) -> SyncOptions {
+        SyncOptions {
+            verify: self.verify.then(|| verify_mode(self.checksum)),
+            force_delete: self.force_delete,
+            rebind: self.rebind,
+        }
+    }
+
+    pub fn report_location(&self) -> Option<&PathBuf> {
+        self.report.as_ref()
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct WatchSubCommand {
+    #[clap(flatten)]
+    pub sync: SyncSubCommand,
+
+    #[clap(flatten)]
+    pub watch: WatchArgs,
+}
+
+/// Options of the commands that keep syncing.
+#[derive(Debug, Parser)]
+pub struct WatchArgs {
+    /// Seconds between syncs.
+    #[clap(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+
+    /// Max seconds between syncs while they keep failing, the delay doubles after each failure.
+    #[clap(long, default_value_t = 3600)]
+    max_backoff: u64,
+
+    /// Don't listen to the Nextcloud `notify_push` websocket, sync only every interval.
+    #[clap(long)]
+    no_push: bool,
+}
+
+impl WatchArgs {
+    pub fn options(&self) -> WatchOptions {
+        WatchOptions {
+            interval: Duration::from_secs(self.interval),
+            max_backoff: Duration::from_secs(self.max_backoff),
+            push: !self.no_push,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct DaemonSubCommand {
+    /// Location of the config file. If not set, try to load `./nube-sync.config.toml`
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Location of the control socket. If not set, `$XDG_RUNTIME_DIR/nubesync.sock`
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics in `http://<ADDRESS>/metrics`, like `127.0.0.1:9184`.
+    #[clap(long)]
+    metrics_address: Option<SocketAddr>,
+
+    #[clap(flatten)]
+    pub watch: WatchArgs,
+}
+
+impl DaemonSubCommand {
+    pub fn config_location(&self) -> PathBuf {
+        self.config.clone().unwrap_or_else(default_config_location)
+    }
+
+    pub fn socket_location(&self) -> PathBuf {
+        self.socket.clone().unwrap_or_else(default_socket_location)
+    }
+
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct CtlSubCommand {
+    /// Location of the control socket. If not set, `$XDG_RUNTIME_DIR/nubesync.sock`
+    #[clap(long)]
+    socket: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub cmd: CtlCommand,
+}
+
+impl CtlSubCommand {
+    pub fn socket_location(&self) -> PathBuf {
+        self.socket.clone().unwrap_or_else(default_socket_location)
+    }
+}
+
+#[derive(Debug, Parser)]
+pub enum CtlCommand {
+    /// Print the state of every pair.
+    Status,
+
+    /// Stop syncing after the current transfer, every pair if not set.
+    Pause { pair: Option<String> },
+
+    /// Sync the paused pairs again, every pair if not set.
+    Resume { pair: Option<String> },
+
+    /// Sync a pair right away, it fails if the pair is paused.
+    SyncNow { pair: String },
+
+    /// List the synced files changed on disk, they are overwritten in the next sync.
+    /// It fails while the pair syncs.
+    ListConflicts { pair: Option<String> },
+
+    /// Print the events of the daemon as they happen, one JSON object per line.
+    Events,
+}
+
+impl CtlCommand {
+    /// Method and params of the request to the daemon.
+    pub fn request(&self) -> (&'static str, Value) {
+        match self {
+            CtlCommand::Status => ("status", Value::Null),
+            CtlCommand::Pause { pair } => ("pause", json!({ "pair": pair })),
+            CtlCommand::Resume { pair } => ("resume", json!({ "pair": pair })),
+            CtlCommand::SyncNow { pair } => ("sync-now", json!({ "pair": pair })),
+            CtlCommand::ListConflicts { pair } => ("list-conflicts", json!({ "pair": pair })),
+            CtlCommand::Events => ("events", Value::Null),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct VerifySubCommand {
+    #[clap(flatten)]
+    pub remote: RemoteArgs,
+
+    /// Also compare the content checksum of each file.
+    #[clap(long)]
+    checksum: bool,
+}
+
+impl VerifySubCommand {
+    pub fn verify_mode(&self) -> VerifyMode {
+        verify_mode(self.checksum)
+    }
+}
+
+fn verify_mode(checksum: bool) -> VerifyMode {
+    if checksum {
+        VerifyMode::Content
+    } else {
+        VerifyMode::Metadata
+    }
+}
+
+/// Arguments to find the local db of an out directory.
+#[derive(Debug, Parser)]
+pub struct DbLocationArgs {
+    /// Directory where the files are synced.
     #[clap(value_parser)]
     pub out: PathBuf,
+
+    /// Remote location synced in the out directory, needed to find the local db when it is
+    /// stored in the default location.
+    #[clap(long)]
+    remote: Option<PathBuf>,
+
+    /// Location of the config file. If not set, try to load `./nube-sync.config.toml`
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Location of the local db, overrides the one found with the config.
+    #[clap(long)]
+    db: Option<PathBuf>,
+}
+
+impl DbLocationArgs {
+    /// Location of the local db. Without `--db`, `--remote` or `--config` it is found
+    /// with the marker left in the out directory, or stored there by older versions.
+    pub fn db_location(&self) -> AppResult<PathBuf> {
+        if let Some(db) = &self.db {
+            return Ok(db.clone());
+        }
+
+        if self.remote.is_none() && self.config.is_none() {
+            let found = store::find_legacy(&self.out).or_else(|| store::find_moved(&self.out));
+            return found.ok_or_else(|| {
+                format!(
+                    "no local db found for {}, pass --config and --remote, or --db",
+                    self.out.display()
+                )
+                .into()
+            });
+        }
+
+        let config_location = self.config.clone().unwrap_or_else(default_config_location);
+        let mut config = Config::load_from_file(config_location)?;
+        config.out_dir.clone_from(&self.out);
+
+        match &self.remote {
+            Some(remote) => config.db_location(&normalize_remote_location(remote)),
+            None => config
+                .db_path
+                .ok_or_else(|| "db_path is not set in config, use --remote or --db".into()),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct ClearSubCommand {
+    #[clap(flatten)]
+    pub location: DbLocationArgs,
+
+    /// Delete every file in the out directory, including the ones never synced.
+    #[clap(long)]
+    all: bool,
+
+    /// Only print the files that would be deleted.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Wait for other nubesync runs on the same local db to finish instead of failing.
+    #[clap(long)]
+    wait: bool,
+}
+
+impl ClearSubCommand {
+    pub fn options(&self) -> ClearOptions {
+        ClearOptions {
+            all: self.all,
+            dry_run: self.dry_run,
+            wait: self.wait,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+pub enum TrashSubCommand {
+    /// List the entries inside the trash of the out directory.
+    List {
+        /// Directory where the files are synced.
+        #[clap(value_parser)]
+        out: PathBuf,
+    },
+
+    /// Move back to the out directory the entries of a trash batch.
+    /// Restored entries are no longer synced, later syncs leave them alone.
+    Restore {
+        /// Directory where the files are synced.
+        #[clap(value_parser)]
+        out: PathBuf,
+
+        /// Batch to restore, as shown by `trash list`.
+        #[clap(value_parser)]
+        batch: String,
+
+        /// Restore only this path of the batch, relative to the out directory.
+        #[clap(value_parser)]
+        path: Option<PathBuf>,
+    },
+
+    /// Erase the trash batches.
+    Purge {
+        /// Directory where the files are synced.
+        #[clap(value_parser)]
+        out: PathBuf,
+
+        /// Erase only the batches older than these days.
+        #[clap(long)]
+        older_than_days: Option<u64>,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub enum DbSubCommand {
+    /// Move the local version database of the out directory to another backend.
+    Convert {
+        #[clap(flatten)]
+        location: DbLocationArgs,
+
+        /// Backend to use from now on.
+        #[clap(long, value_enum)]
+        to: DbBackend,
+    },
+
+    /// Print the entry recorded for a remote href or a local path.
+    Show {
+        #[clap(flatten)]
+        location: DbLocationArgs,
+
+        /// Remote href of the entry, as sent by the server.
+        #[clap(long, conflicts_with = "path", required_unless_present = "path")]
+        href: Option<String>,
+
+        /// Local path of the entry.
+        #[clap(long)]
+        path: Option<PathBuf>,
+    },
 }
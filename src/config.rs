@@ -1,9 +1,19 @@
 use std::{fs::File, io::Read, path::PathBuf};
 
+use sha1::{Digest, Sha1};
+
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::result::AppResult;
+use crate::{
+    archive::Versioning,
+    bandwidth::{BandwidthSchedule, Rate},
+    hooks::Hooks,
+    notifications::Notifications,
+    result::AppResult,
+    store::DbBackend,
+    trash::DeletionMode,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -12,9 +22,75 @@ pub struct Config {
     pub password: String,
     pub out_dir: PathBuf,
     pub black_list: Vec<String>,
+    /// Max download rate shared by all transfers, like `2MiB/s`. Unlimited if not set.
+    #[serde(default)]
+    pub bandwidth_limit: Option<Rate>,
+    /// Rates for specific hours of the day, like `"08:00-18:00" = "1MiB/s"`.
+    #[serde(default)]
+    pub bandwidth_schedule: BandwidthSchedule,
+    /// Abort the sync if it would delete more than this percent of the tracked entries.
+    #[serde(default)]
+    pub max_delete_percent: Option<f64>,
+    /// Abort the sync if it would delete more than this number of entries.
+    #[serde(default)]
+    pub max_delete_count: Option<usize>,
+    /// What to do with local entries removed on the server.
+    #[serde(default)]
+    pub deletion_mode: DeletionMode,
+    /// With `deletion_mode = "trash"`, erase the trash batches older than these days after each sync.
+    #[serde(default)]
+    pub trash_retention_days: Option<u64>,
+    /// Keep the previous copy of overwritten files in `.nubesync-versions/` if set.
+    #[serde(default)]
+    pub versioning: Option<Versioning>,
+    /// Backend of the local version database, an existing database is converted if it differs.
+    #[serde(default)]
+    pub db_backend: DbBackend,
+    /// Location of the local version database. If not set, it is stored in
+    /// `$XDG_STATE_HOME/nubesync/` with a name unique for each host, remote location and out dir.
+    #[serde(default)]
+    pub db_path: Option<PathBuf>,
+    /// Commands run before and after each sync and for each synced file.
+    #[serde(default)]
+    pub hooks: Hooks,
+    /// Webhook and desktop notifications of the sync outcomes.
+    #[serde(default)]
+    pub notifications: Notifications,
+    /// Remote locations and out dirs synced by `nubesync daemon`.
+    #[serde(default)]
+    pub pairs: Vec<SyncPair>,
+}
+
+/// Remote location synced to a local out dir.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPair {
+    /// Name used by `nubesync ctl`, the remote location if not set.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub remote: String,
+    pub out_dir: PathBuf,
+    /// Location of the local version database, the default one of the pair if not set.
+    #[serde(default)]
+    pub db_path: Option<PathBuf>,
+}
+
+impl SyncPair {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.remote)
+    }
 }
 
 impl Config {
+    /// Config to sync `pair`, the other options are shared by every pair.
+    pub fn for_pair(&self, pair: &SyncPair) -> Config {
+        Config {
+            out_dir: pair.out_dir.clone(),
+            db_path: pair.db_path.clone(),
+            pairs: Vec::new(),
+            ..self.clone()
+        }
+    }
+
     pub fn load_from_file(path: PathBuf) -> AppResult<Self> {
         let mut file_content = String::new();
         let _ = File::open(path)?.read_to_string(&mut file_content)?;
@@ -22,4 +98,31 @@ impl Config {
 
         Ok(config)
     }
+
+    /// Location of the local version database used to sync `remote_dir` into the out dir.
+    pub fn db_location(&self, remote_dir: &str) -> AppResult<PathBuf> {
+        if let Some(db_path) = &self.db_path {
+            return Ok(db_path.clone());
+        }
+
+        let state_home = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .ok_or("cannot find the state directory, neither XDG_STATE_HOME nor HOME are set")?;
+
+        let out_dir = std::path::absolute(&self.out_dir)?;
+        let mut hasher = Sha1::new();
+        hasher.update(self.host.as_str());
+        hasher.update("\n");
+        hasher.update(remote_dir);
+        hasher.update("\n");
+        hasher.update(out_dir.as_os_str().as_encoded_bytes());
+        let pair_hash = format!("{:x}", hasher.finalize());
+
+        Ok(state_home
+            .join("nubesync")
+            .join(format!("{}.db", &pair_hash[..16])))
+    }
 }
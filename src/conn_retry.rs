@@ -1,4 +1,10 @@
-use std::{future::Future, sync::LazyLock, time::Duration};
+use std::{
+    future::Future,
+    sync::{atomic::Ordering, LazyLock},
+    time::Duration,
+};
+
+use crate::metrics::METRICS;
 
 pub static DEFAULT_CONN_RETRY: LazyLock<ConnRetry> = LazyLock::new(ConnRetry::default);
 
@@ -24,6 +30,7 @@ impl ConnRetry {
                     if tries == 0 {
                         return Err(err);
                     }
+                    METRICS.retries.fetch_add(1, Ordering::Relaxed);
                     tokio::time::sleep(self.delay).await;
                 }
             }
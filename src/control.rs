@@ -0,0 +1,539 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    sync::{broadcast, Notify},
+};
+use tracing::{error, warn};
+
+use crate::{
+    events::{Event, EventKind, EventSink},
+    result::AppResult,
+    versions::{LocalVersion, Tampering},
+};
+
+/// Location of the control socket when it is not set.
+pub fn default_socket_location() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("nubesync.sock"),
+        None => {
+            let uid = unsafe { libc::getuid() };
+            std::env::temp_dir().join(format!("nubesync-{}.sock", uid))
+        }
+    }
+}
+
+/// State of a pair shared by its watch loop and the control socket.
+#[derive(Debug)]
+pub struct PairControl {
+    pub name: String,
+    pub remote: String,
+    pub out_dir: PathBuf,
+    paused: AtomicBool,
+    wake: Notify,
+    status: Mutex<PairStatus>,
+    /// Entries synced by the pair, unset while it syncs or starts.
+    version: Mutex<Option<Arc<LocalVersion>>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PairStatus {
+    syncing: bool,
+    last_sync: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+impl PairControl {
+    pub fn new(name: &str, remote: &str, out_dir: &Path) -> Self {
+        Self {
+            name: name.to_string(),
+            remote: remote.to_string(),
+            out_dir: out_dir.to_path_buf(),
+            paused: AtomicBool::new(false),
+            wake: Notify::new(),
+            status: Mutex::new(PairStatus::default()),
+            version: Mutex::new(None),
+        }
+    }
+
+    /// Share the entries synced by the pair, `None` while they change.
+    pub fn set_version(&self, version: Option<LocalVersion>) {
+        *self.version.lock().unwrap() = version.map(Arc::new);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Wait until a sync is requested or the pair is resumed.
+    pub async fn woken(&self) {
+        self.wake.notified().await
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.wake.notify_one();
+    }
+
+    fn status(&self) -> Value {
+        let status = self.status.lock().unwrap().clone();
+        let state = match (self.is_paused(), status.syncing) {
+            (_, true) => "syncing",
+            (true, false) => "paused",
+            (false, false) => "idle",
+        };
+
+        json!({
+            "pair": self.name,
+            "remote": self.remote,
+            "out_dir": self.out_dir,
+            "state": state,
+            "last_sync": status.last_sync,
+            "last_error": status.last_error,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Server side of the control socket of the daemon, speaking JSON-RPC 2.0 with a request per line.
+#[derive(Debug)]
+pub struct ControlServer {
+    location: PathBuf,
+    pairs: Mutex<Vec<Arc<PairControl>>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl ControlServer {
+    pub fn new(location: PathBuf, events: broadcast::Sender<Event>) -> Self {
+        Self {
+            location,
+            pairs: Mutex::new(Vec::new()),
+            events,
+        }
+    }
+
+    /// Replace the pairs managed through the socket.
+    pub fn set_pairs(&self, pairs: Vec<Arc<PairControl>>) {
+        *self.pairs.lock().unwrap() = pairs;
+    }
+
+    /// Create the socket, replacing the one left by a daemon no longer running.
+    pub fn bind(&self) -> AppResult<UnixListener> {
+        if self.location.exists() {
+            if std::os::unix::net::UnixStream::connect(&self.location).is_ok() {
+                return Err(
+                    format!("another daemon is listening in {}", self.location.display()).into(),
+                );
+            }
+
+            std::fs::remove_file(&self.location)?;
+        }
+
+        if let Some(parent) = self.location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = UnixListener::bind(&self.location)?;
+        std::fs::set_permissions(&self.location, std::fs::Permissions::from_mode(0o600))?;
+
+        Ok(listener)
+    }
+
+    /// Answer the clients of `listener`, each one in its own local task.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!(%err, "error accepting control connection");
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            tokio::task::spawn_local(async move {
+                if let Err(err) = server.handle(stream).await {
+                    warn!(%err, "error in control connection");
+                }
+            });
+        }
+    }
+
+    /// Keep the status of the pairs updated with their events.
+    pub async fn track_status(self: Arc<Self>) {
+        let mut events = self.events.subscribe();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+
+            let Some(pair) = self.find_pair(&event.pair) else {
+                continue;
+            };
+
+            let mut status = pair.status.lock().unwrap();
+            match event.kind {
+                EventKind::SyncStarted => status.syncing = true,
+                EventKind::SyncFinished { error } => {
+                    status.syncing = false;
+                    status.last_sync = Some(event.time);
+                    status.last_error = error;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> AppResult<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let request: Request = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(err) => {
+                    let response = error_response(Value::Null, -32700, &err.to_string());
+                    write_line(&mut writer, &response).await?;
+                    continue;
+                }
+            };
+
+            if request.method == "events" {
+                let mut events = self.events.subscribe();
+                write_line(&mut writer, &result_response(request.id, json!(true))).await?;
+                return stream_events(&mut events, &mut writer).await;
+            }
+
+            let response = match self.call(&request.method, &request.params).await {
+                Ok(result) => result_response(request.id, result),
+                Err((code, message)) => error_response(request.id, code, &message),
+            };
+            write_line(&mut writer, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let pair_param = params.get("pair").and_then(Value::as_str);
+        match method {
+            "status" => {
+                let pairs = self.pairs.lock().unwrap();
+                Ok(Value::Array(
+                    pairs.iter().map(|pair| pair.status()).collect(),
+                ))
+            }
+            "pause" | "resume" => {
+                let paused = method == "pause";
+                for pair in self.select_pairs(pair_param)? {
+                    if pair.is_paused() != paused {
+                        pair.set_paused(paused);
+                        let kind = if paused {
+                            EventKind::Paused
+                        } else {
+                            EventKind::Resumed
+                        };
+                        EventSink::new(&pair.name, self.events.clone()).emit(kind);
+                    }
+                }
+
+                Ok(json!(true))
+            }
+            "sync-now" => {
+                let name = pair_param.ok_or((-32602, "missing pair param".to_string()))?;
+                for pair in self.select_pairs(Some(name))? {
+                    if pair.is_paused() {
+                        return Err((-32000, format!("pair {} is paused, resume it", name)));
+                    }
+                    pair.wake.notify_one();
+                }
+
+                Ok(json!(true))
+            }
+            "list-conflicts" => {
+                let mut conflicts = Vec::new();
+                for pair in self.select_pairs(pair_param)? {
+                    let version = pair.version.lock().unwrap().clone().ok_or_else(|| {
+                        (
+                            -32000,
+                            format!("pair {} is syncing, try again once it finishes", pair.name),
+                        )
+                    })?;
+                    // reads every file, out of the thread running the syncs
+                    let found = tokio::task::spawn_blocking(move || {
+                        list_conflicts(&pair.name, &version).map_err(|err| err.to_string())
+                    })
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|found| found)
+                    .map_err(|err| (-32000, err))?;
+                    conflicts.extend(found);
+                }
+
+                Ok(Value::Array(conflicts))
+            }
+            _ => Err((-32601, format!("unknown method: {}", method))),
+        }
+    }
+
+    fn find_pair(&self, name: &str) -> Option<Arc<PairControl>> {
+        let pairs = self.pairs.lock().unwrap();
+        pairs.iter().find(|pair| pair.name == name).cloned()
+    }
+
+    /// The pair called `name`, or every pair if not set.
+    fn select_pairs(&self, name: Option<&str>) -> Result<Vec<Arc<PairControl>>, (i64, String)> {
+        match name {
+            Some(name) => match self.find_pair(name) {
+                Some(pair) => Ok(vec![pair]),
+                None => Err((-32602, format!("unknown pair: {}", name))),
+            },
+            None => Ok(self.pairs.lock().unwrap().clone()),
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.location);
+    }
+}
+
+/// Synced files changed on disk since their download, they are overwritten in the next sync.
+fn list_conflicts(pair: &str, version: &LocalVersion) -> AppResult<Vec<Value>> {
+    let mut conflicts = Vec::new();
+    for (href, file) in version.iter() {
+        if file.is_dir {
+            continue;
+        }
+
+        match file.verify(false)? {
+            None | Some(Tampering::Missing) => {}
+            Some(reason) => conflicts.push(json!({
+                "pair": pair,
+                "href": href,
+                "path": file.path,
+                "reason": reason.to_string(),
+            })),
+        }
+    }
+
+    Ok(conflicts)
+}
+
+async fn stream_events(
+    events: &mut broadcast::Receiver<Event>,
+    writer: &mut OwnedWriteHalf,
+) -> AppResult<()> {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "event",
+            "params": event,
+        });
+        write_line(writer, &notification).await?;
+    }
+}
+
+fn result_response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+async fn write_line(writer: &mut OwnedWriteHalf, message: &Value) -> AppResult<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+/// Client side of the control socket, used by `nubesync ctl`.
+pub struct ControlClient {
+    lines: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+}
+
+impl ControlClient {
+    pub async fn connect(location: &Path) -> AppResult<Self> {
+        let stream = UnixStream::connect(location).await.map_err(|err| {
+            format!(
+                "can't connect to the daemon in {}: {}",
+                location.display(),
+                err
+            )
+        })?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 1,
+        })
+    }
+
+    /// Call `method` and wait for its result.
+    pub async fn call(&mut self, method: &str, params: Value) -> AppResult<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        write_line(&mut self.writer, &request).await?;
+
+        let mut response = self.next_message().await?;
+        if let Some(error) = response.get("error") {
+            let message = error.get("message").and_then(Value::as_str).unwrap_or("");
+            return Err(format!("daemon error: {}", message).into());
+        }
+
+        Ok(response["result"].take())
+    }
+
+    /// Next message sent by the daemon, like the notifications after subscribing to `events`.
+    pub async fn next_message(&mut self) -> AppResult<Value> {
+        let line = self
+            .lines
+            .next_line()
+            .await?
+            .ok_or("connection closed by the daemon")?;
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::LocalSet;
+
+    use super::*;
+    use crate::versions::LocalFile;
+
+    #[tokio::test]
+    async fn requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("nubesync.sock");
+        let (events, _) = broadcast::channel(16);
+        let server = Arc::new(ControlServer::new(location.clone(), events));
+        let pair = Arc::new(PairControl::new("docs", "/docs", dir.path()));
+        server.set_pairs(vec![pair]);
+        let listener = server.bind().unwrap();
+
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                tokio::task::spawn_local(server.clone().serve(listener));
+                let mut client = ControlClient::connect(&location).await.unwrap();
+                let state = |status: Value| status[0]["state"].as_str().unwrap().to_string();
+
+                let status = client.call("status", json!({})).await.unwrap();
+                assert_eq!(status[0]["pair"], "docs");
+                assert_eq!(state(status), "idle");
+
+                client
+                    .call("pause", json!({ "pair": "docs" }))
+                    .await
+                    .unwrap();
+                let status = client.call("status", json!({})).await.unwrap();
+                assert_eq!(state(status), "paused");
+                let err = client
+                    .call("sync-now", json!({ "pair": "docs" }))
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("paused"), "{}", err);
+
+                client.call("resume", json!({})).await.unwrap();
+                let status = client.call("status", json!({})).await.unwrap();
+                assert_eq!(state(status), "idle");
+                client
+                    .call("sync-now", json!({ "pair": "docs" }))
+                    .await
+                    .unwrap();
+
+                let err = client
+                    .call("pause", json!({ "pair": "photos" }))
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("unknown pair"), "{}", err);
+                let err = client.call("rename", json!({})).await.unwrap_err();
+                assert!(err.to_string().contains("unknown method"), "{}", err);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn conflicts_of_the_synced_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("nubesync.sock");
+        let (events, _) = broadcast::channel(16);
+        let server = Arc::new(ControlServer::new(location.clone(), events));
+        let pair = Arc::new(PairControl::new("docs", "/docs", dir.path()));
+        server.set_pairs(vec![pair.clone()]);
+        let listener = server.bind().unwrap();
+
+        let changed = dir.path().join("changed.txt");
+        std::fs::write(&changed, "changed locally").unwrap();
+        let mut version = LocalVersion::default();
+        version.add(
+            "/docs/changed.txt".to_string(),
+            LocalFile {
+                path: changed,
+                is_dir: false,
+                last_modified: None,
+                local_modified: None,
+                size: Some(1),
+                checksum: None,
+                file_id: None,
+            },
+        );
+
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                tokio::task::spawn_local(server.clone().serve(listener));
+                let mut client = ControlClient::connect(&location).await.unwrap();
+
+                // not loaded yet, or syncing
+                let err = client.call("list-conflicts", json!({})).await.unwrap_err();
+                assert!(err.to_string().contains("syncing"), "{}", err);
+
+                pair.set_version(Some(version));
+                let conflicts = client.call("list-conflicts", json!({})).await.unwrap();
+                assert_eq!(conflicts[0]["href"], "/docs/changed.txt");
+                assert_eq!(conflicts[0]["pair"], "docs");
+            })
+            .await;
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, watch},
+    task::{JoinHandle, LocalSet},
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::{
+    bandwidth::BandwidthLimiter,
+    cli::normalize_remote_location,
+    config::Config,
+    control::{ControlServer, PairControl},
+    events::{Event, EventSink},
+    metrics::{self, METRICS},
+    notifications::Notifier,
+    result::AppResult,
+    sync_service::{SyncOptions, SyncService, WatchOptions},
+    systemd,
+};
+
+/// Events kept for slow subscribers of the control socket.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Keeps every pair of the config in sync until SIGTERM, managed through the control socket.
+///
+/// SIGHUP loads the config again and restarts the pairs with it,
+/// the running ones are kept if the new config is not valid.
+pub struct Daemon {
+    config_location: PathBuf,
+    watch: WatchOptions,
+    control: Arc<ControlServer>,
+    events: broadcast::Sender<Event>,
+    metrics_address: Option<SocketAddr>,
+    /// Shared by the pairs and kept across reloads, so the rate limit of the notifications holds.
+    notifier: Notifier,
+}
+
+/// Pairs being synced with the same config.
+struct Running {
+    stop: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Daemon {
+    pub fn new(
+        config_location: PathBuf,
+        watch: WatchOptions,
+        socket_location: PathBuf,
+        metrics_address: Option<SocketAddr>,
+    ) -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        Self {
+            config_location,
+            watch,
+            control: Arc::new(ControlServer::new(socket_location, events.clone())),
+            events,
+            metrics_address,
+            notifier: Notifier::default(),
+        }
+    }
+
+    pub async fn run(&self) -> AppResult<()> {
+        let config = self.load_config()?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let watchdog = systemd::watchdog_interval();
+        let listener = self.control.bind()?;
+        let metrics_listener = match self.metrics_address {
+            Some(address) => Some(metrics::bind(address).await?),
+            None => None,
+        };
+
+        // the sync futures are not `Send`, so they run in this thread
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                tokio::task::spawn_local(self.control.clone().serve(listener));
+                tokio::task::spawn_local(self.control.clone().track_status());
+                if let Some(metrics_listener) = metrics_listener {
+                    tokio::task::spawn_local(metrics::serve(metrics_listener));
+                }
+                // in its own task, so it keeps pinging while the pairs are restarted
+                if let Some(interval) = watchdog {
+                    tokio::task::spawn_local(ping_watchdog(interval));
+                }
+                let mut running = self.start(&config);
+                notify("READY=1");
+
+                loop {
+                    tokio::select! {
+                        _ = hangup.recv() => {
+                            notify("RELOADING=1");
+                            match self.load_config() {
+                                Ok(config) => {
+                                    info!("config reloaded, restarting pairs...");
+                                    running.stop().await;
+                                    running = self.start(&config);
+                                }
+                                Err(err) => error!(
+                                    %err,
+                                    "error reloading config, keeping the previous one"
+                                ),
+                            }
+                            notify("READY=1");
+                        }
+                        _ = terminate.recv() => break,
+                        _ = interrupt.recv() => break,
+                    }
+                }
+
+                info!("finishing the current transfers...");
+                notify("STOPPING=1");
+                running.stop().await;
+            })
+            .await;
+
+        Ok(())
+    }
+
+    fn load_config(&self) -> AppResult<Config> {
+        let config = Config::load_from_file(self.config_location.clone())?;
+        if config.pairs.is_empty() {
+            return Err("no pairs in config, add them in [[pairs]] sections".into());
+        }
+
+        Ok(config)
+    }
+
+    /// Start watching every pair of `config` in the current `LocalSet`.
+    /// The pairs share the bandwidth limit of the config.
+    fn start(&self, config: &Config) -> Running {
+        let (stop, shutdown) = watch::channel(false);
+        let bandwidth =
+            BandwidthLimiter::new(config.bandwidth_limit, config.bandwidth_schedule.clone());
+        let mut pairs = Vec::new();
+        let mut tasks = Vec::new();
+        for pair in &config.pairs {
+            let config = config.for_pair(pair);
+            let remote = normalize_remote_location(Path::new(&pair.remote));
+            let db_location = match config.db_location(&remote) {
+                Ok(db_location) => db_location,
+                Err(err) => {
+                    error!(pair = pair.name(), %err, "error finding the local db");
+                    continue;
+                }
+            };
+
+            let control = Arc::new(PairControl::new(pair.name(), &remote, &pair.out_dir));
+            pairs.push(control.clone());
+
+            let events = EventSink::new(pair.name(), self.events.clone());
+            let metrics = METRICS.pair(pair.name());
+            let mut shutdown = shutdown.clone();
+            let bandwidth = bandwidth.clone();
+            let notifier = self.notifier.clone();
+            let options = self.watch;
+            let span = info_span!("pair", name = pair.name());
+            tasks.push(tokio::task::spawn_local(
+                async move {
+                    let mut delay = options.interval;
+                    loop {
+                        let result = async {
+                            let mut sync = SyncService::init_with_limiter(
+                                config.clone(),
+                                &db_location,
+                                false,
+                                bandwidth.clone(),
+                            )
+                            .await?;
+                            sync.set_shutdown(shutdown.clone());
+                            sync.set_events(events.clone());
+                            sync.set_control(control.clone());
+                            sync.set_metrics(metrics.clone());
+                            sync.set_notifier(notifier.clone());
+
+                            sync.watch(&remote, SyncOptions::default(), options).await
+                        }
+                        .await;
+
+                        let err = match result {
+                            Err(err) if !*shutdown.borrow() => err,
+                            _ => break,
+                        };
+                        error!(%err, retry_in = delay.as_secs(), "error syncing pair");
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = shutdown.wait_for(|stop| *stop) => break,
+                        }
+                        delay = (delay * 2).min(options.max_backoff.max(options.interval));
+                    }
+                }
+                .instrument(span),
+            ));
+        }
+        self.control.set_pairs(pairs);
+
+        Running { stop, tasks }
+    }
+}
+
+impl Running {
+    /// Ask every pair to stop after its current transfer and wait for them.
+    async fn stop(self) {
+        let _ = self.stop.send(true);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Tell systemd the daemon is alive every `interval`.
+async fn ping_watchdog(interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+fn notify(state: &str) {
+    if let Err(err) = systemd::notify(state) {
+        warn!(%err, "error notifying systemd");
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use reqwest::Method;
+use reqwest_dav::{
+    list_cmd::{ListEntity, ListMultiStatus},
+    Client, DecodeError, Error, StatusMismatchedError,
+};
+use serde::Deserialize;
+
+use crate::{checksum::Checksum, metrics::METRICS, versions::Href};
+
+/// PROPFIND body asking for the standard properties plus the Nextcloud ones.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<d:propfind xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+    <d:prop>
+        <d:getlastmodified/>
+        <d:resourcetype/>
+        <d:getetag/>
+        <d:getcontentlength/>
+        <d:getcontenttype/>
+        <d:quota-used-bytes/>
+        <d:quota-available-bytes/>
+        <oc:checksums/>
+        <oc:fileid/>
+    </d:prop>
+</d:propfind>
+"#;
+
+/// Header with the checksum of the content sent in a GET response.
+pub const CHECKSUM_HEADER: &str = "OC-Checksum";
+
+/// Server entities with the Nextcloud properties found for each one.
+#[derive(Debug, Clone)]
+pub struct DavListing {
+    pub entities: Vec<ListEntity>,
+    pub props: HashMap<Href, OcProps>,
+}
+
+/// Nextcloud (`oc:`) properties of an entity.
+#[derive(Debug, Clone, Default)]
+pub struct OcProps {
+    pub checksums: Vec<Checksum>,
+    pub file_id: Option<String>,
+}
+
+impl OcProps {
+    /// Strongest checksum known for the entity.
+    pub fn checksum(&self) -> Option<&Checksum> {
+        self.checksums.first()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OcMultiStatus {
+    #[serde(rename = "response")]
+    responses: Vec<OcResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcResponse {
+    href: String,
+    #[serde(rename = "propstat")]
+    prop_stat: Vec<OcPropStat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcPropStat {
+    status: String,
+    prop: OcProp,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcProp {
+    #[serde(default)]
+    checksums: Option<OcChecksums>,
+    #[serde(default)]
+    fileid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OcChecksums {
+    #[serde(default)]
+    checksum: Vec<String>,
+}
+
+impl OcResponse {
+    fn into_props(self) -> (Href, OcProps) {
+        let mut props = OcProps::default();
+        for prop_stat in self.prop_stat {
+            let is_ok = prop_stat
+                .status
+                .split_whitespace()
+                .nth(1)
+                .is_some_and(|code| code.starts_with('2'));
+            if !is_ok {
+                continue;
+            }
+
+            if let Some(checksums) = prop_stat.prop.checksums {
+                for list in checksums.checksum {
+                    props.checksums.extend(Checksum::parse_list(&list));
+                }
+            }
+
+            if let Some(file_id) = prop_stat.prop.fileid.filter(|id| !id.is_empty()) {
+                props.file_id = Some(file_id);
+            }
+        }
+
+        (self.href, props)
+    }
+}
+
+/// List recursively `path`, including the Nextcloud properties of each entity.
+pub async fn list(client: &Client, path: &str) -> Result<DavListing, Error> {
+    let response = METRICS
+        .propfind_latency
+        .time(
+            client
+                .start_request(Method::from_bytes(b"PROPFIND").unwrap(), path)
+                .await?
+                .header("depth", "infinity")
+                .body(PROPFIND_BODY)
+                .send(),
+        )
+        .await?;
+
+    let code = response.status();
+    if !code.is_success() {
+        return Err(Error::Decode(DecodeError::StatusMismatched(
+            StatusMismatchedError {
+                response_code: code.as_u16(),
+                expected_code: 207,
+            },
+        )));
+    }
+
+    let body = response.text().await?;
+    let multi_status: ListMultiStatus = serde_xml_rs::from_str(&body)?;
+    let entities = multi_status
+        .responses
+        .into_iter()
+        .map(ListEntity::try_from)
+        .collect::<Result<_, _>>()?;
+
+    let oc_multi_status: OcMultiStatus = serde_xml_rs::from_str(&body)?;
+    let props = oc_multi_status
+        .responses
+        .into_iter()
+        .map(OcResponse::into_props)
+        .collect();
+
+    Ok(DavListing { entities, props })
+}
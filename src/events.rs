@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::versions::Href;
+
+/// Something that happened while syncing a pair.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub pair: String,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    SyncStarted,
+    SyncFinished { error: Option<String> },
+    FileDownloaded { href: Href, path: PathBuf },
+    FileDeleted { href: Href, path: PathBuf },
+    Paused,
+    Resumed,
+}
+
+/// Publishes the events of a pair to every subscriber of `sender`.
+#[derive(Debug, Clone)]
+pub struct EventSink {
+    pair: String,
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventSink {
+    pub fn new(pair: &str, sender: broadcast::Sender<Event>) -> Self {
+        Self {
+            pair: pair.to_string(),
+            sender,
+        }
+    }
+
+    pub fn emit(&self, kind: EventKind) {
+        // without subscribers the event is dropped
+        let _ = self.sender.send(Event {
+            pair: self.pair.clone(),
+            time: Utc::now(),
+            kind,
+        });
+    }
+}
@@ -0,0 +1,300 @@
+use std::{ffi::OsStr, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, Command};
+use tracing::{debug, warn};
+
+use crate::{result::AppResult, sync_service::Shutdown};
+
+/// Shell commands run around the syncs, with the details in `NUBESYNC_*` environment variables.
+/// The file hooks run one at a time, the sync waits for each before the next transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hooks {
+    /// Run before each sync, the sync is aborted if it fails.
+    #[serde(default)]
+    pub pre_sync: Option<String>,
+    /// Run after each sync, even if it failed.
+    #[serde(default)]
+    pub post_sync: Option<String>,
+    /// Run after downloading each file.
+    #[serde(default)]
+    pub on_file_downloaded: Option<String>,
+    /// Run after deleting each local entry removed on the server.
+    #[serde(default)]
+    pub on_file_deleted: Option<String>,
+    /// Max seconds a hook can run before it and the commands it started are killed.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre_sync: None,
+            post_sync: None,
+            on_file_downloaded: None,
+            on_file_deleted: None,
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Hook {
+    PreSync,
+    PostSync,
+    FileDownloaded,
+    FileDeleted,
+}
+
+impl Hook {
+    fn name(&self) -> &'static str {
+        match self {
+            Hook::PreSync => "pre_sync",
+            Hook::PostSync => "post_sync",
+            Hook::FileDownloaded => "on_file_downloaded",
+            Hook::FileDeleted => "on_file_deleted",
+        }
+    }
+
+    /// Value of `NUBESYNC_ACTION`.
+    fn action(&self) -> &'static str {
+        match self {
+            Hook::PreSync => "pre_sync",
+            Hook::PostSync => "post_sync",
+            Hook::FileDownloaded => "downloaded",
+            Hook::FileDeleted => "deleted",
+        }
+    }
+}
+
+impl Hooks {
+    fn command(&self, hook: Hook) -> Option<&str> {
+        let command = match hook {
+            Hook::PreSync => &self.pre_sync,
+            Hook::PostSync => &self.post_sync,
+            Hook::FileDownloaded => &self.on_file_downloaded,
+            Hook::FileDeleted => &self.on_file_deleted,
+        };
+
+        command.as_deref()
+    }
+
+    /// Run the command of `hook` with `sh`, if set, failing if it exits with an error.
+    /// `NUBESYNC_ACTION` and `NUBESYNC_OUT_DIR` are set besides `env`. The command, and the ones
+    /// it started, are killed when it runs out of time or the `shutdown` is set while it runs.
+    pub async fn run(
+        &self,
+        hook: Hook,
+        out_dir: &Path,
+        env: &[(&str, &OsStr)],
+        shutdown: Option<Shutdown>,
+    ) -> AppResult<()> {
+        let Some(command) = self.command(hook) else {
+            return Ok(());
+        };
+
+        debug!(hook = hook.name(), command, "running hook");
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("NUBESYNC_ACTION", hook.action())
+            .env("NUBESYNC_OUT_DIR", out_dir)
+            .envs(env.iter().copied())
+            .kill_on_drop(true)
+            .process_group(0)
+            .spawn()
+            .map_err(|err| format!("can't run the {} hook: {}", hook.name(), err))?;
+        let mut group = ProcessGroup::of(&child);
+
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let stopped = async {
+            match shutdown {
+                // a shutdown set before, like the one that stopped the sync, doesn't stop post_sync
+                Some(mut shutdown) => {
+                    shutdown.borrow_and_update();
+                    while shutdown.changed().await.is_ok() {
+                        if *shutdown.borrow_and_update() {
+                            return;
+                        }
+                    }
+                    std::future::pending().await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let status = tokio::select! {
+            status = tokio::time::timeout(timeout, child.wait()) => match status {
+                Ok(status) => status?,
+                Err(_) => {
+                    group.kill(&mut child, hook).await;
+                    return Err(format!(
+                        "{} hook timed out after {} seconds",
+                        hook.name(),
+                        self.timeout_secs
+                    )
+                    .into());
+                }
+            },
+            _ = stopped => {
+                group.kill(&mut child, hook).await;
+                return Err(format!("{} hook stopped by the shutdown", hook.name()).into());
+            }
+        };
+
+        group.release();
+
+        if !status.success() {
+            return Err(format!("{} hook failed: {}", hook.name(), status).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Process group of a running hook, killed when dropped so the commands it started don't
+/// outlive it.
+struct ProcessGroup {
+    id: Option<libc::pid_t>,
+}
+
+impl ProcessGroup {
+    /// Group led by `child`, started with `process_group(0)`.
+    fn of(child: &Child) -> Self {
+        Self {
+            id: child.id().map(|id| id as libc::pid_t),
+        }
+    }
+
+    /// Kill every process of the group and wait for `child` to exit.
+    async fn kill(&mut self, child: &mut Child, hook: Hook) {
+        self.signal();
+        if let Err(err) = child.wait().await {
+            warn!(%err, hook = hook.name(), "error waiting for killed hook");
+        }
+    }
+
+    /// Leave the group alone, the hook exited by itself.
+    fn release(&mut self) {
+        self.id = None;
+    }
+
+    fn signal(&mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+
+        if unsafe { libc::killpg(id, libc::SIGKILL) } != 0 {
+            let err = std::io::Error::last_os_error();
+            warn!(%err, "error killing hook");
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.signal();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    fn hooks(command: &str, timeout_secs: u64) -> Hooks {
+        Hooks {
+            post_sync: Some(command.to_string()),
+            timeout_secs,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn exit_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = [("NUBESYNC_RESULT", OsStr::new("success"))];
+
+        hooks("test \"$NUBESYNC_RESULT\" = success", 5)
+            .run(Hook::PostSync, dir.path(), &env, None)
+            .await
+            .unwrap();
+        let err = hooks("exit 3", 5)
+            .run(Hook::PostSync, dir.path(), &env, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("post_sync hook failed"), "{}", err);
+    }
+
+    /// Whether the process `pid` exited, even if its new parent didn't reap it yet.
+    fn exited(pid: libc::pid_t) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            // the state follows the command name, in parentheses
+            Ok(stat) => stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, rest)| rest.starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[tokio::test]
+    async fn killed_after_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+
+        let started = Instant::now();
+        let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let err = hooks(&command, 1)
+            .run(Hook::PostSync, dir.path(), &[], None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let pid = std::fs::read_to_string(pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !exited(pid) {
+            assert!(Instant::now() < deadline, "sleep {} still running", pid);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_before_start_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_stop, shutdown) = tokio::sync::watch::channel(true);
+
+        hooks("sleep 0.2", 60)
+            .run(Hook::PostSync, dir.path(), &[], Some(shutdown))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn killed_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+
+        let started = Instant::now();
+        let stopping = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stop.send(true).unwrap();
+        });
+        let err = hooks("sleep 30", 60)
+            .run(Hook::PostSync, dir.path(), &[], Some(shutdown))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("shutdown"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        stopping.await.unwrap();
+    }
+}
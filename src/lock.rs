@@ -0,0 +1,228 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tracing::info;
+
+use crate::result::AppResult;
+
+/// Time between attempts when waiting for another run without blocking the thread.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exclusive lock of a local db, held while a command changes the db or its out dir.
+///
+/// It is an advisory lock (`flock`) of the `<db>.lock` file, released by the system when the
+/// owner exits in any way. The file only holds the PID of the owner, to tell who is waited for.
+#[derive(Debug)]
+pub struct RunLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Take the lock of the db in `db_location`. If another process holds it,
+    /// fail right away or, with `wait`, block the thread until it is released.
+    pub fn acquire(db_location: &Path, wait: bool) -> AppResult<RunLock> {
+        let (mut file, path) = open(db_location)?;
+        if try_lock(&file)? {
+            return RunLock::owned(file, path);
+        }
+
+        if !wait {
+            return Err(busy_error(db_location, &mut file));
+        }
+
+        info!(
+            pid = read_owner(&mut file),
+            "waiting for another nubesync process to finish..."
+        );
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        RunLock::owned(file, path)
+    }
+
+    /// Same as `acquire`, but waits in the runtime instead of blocking the thread.
+    pub async fn acquire_async(db_location: &Path, wait: bool) -> AppResult<RunLock> {
+        let (mut file, path) = open(db_location)?;
+        let mut waiting = false;
+        loop {
+            if try_lock(&file)? {
+                return RunLock::owned(file, path);
+            }
+
+            if !wait {
+                return Err(busy_error(db_location, &mut file));
+            }
+
+            if !waiting {
+                info!(
+                    pid = read_owner(&mut file),
+                    "waiting for another nubesync process to finish..."
+                );
+                waiting = true;
+            }
+            tokio::time::sleep(WAIT_INTERVAL).await;
+        }
+    }
+
+    /// Write the PID of this process in the locked `file`.
+    fn owned(mut file: File, path: PathBuf) -> AppResult<RunLock> {
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+
+        Ok(RunLock { file, path })
+    }
+
+    /// Location of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the lock file and release the lock, once the db it guards is removed.
+    pub fn remove(self) -> AppResult<()> {
+        std::fs::remove_file(&self.path)?;
+        drop(self.file);
+
+        Ok(())
+    }
+}
+
+/// Open the lock file of the db in `db_location`, creating its directory if needed.
+fn open(db_location: &Path) -> std::io::Result<(File, PathBuf)> {
+    let mut location = db_location.as_os_str().to_os_string();
+    location.push(".lock");
+    let location = PathBuf::from(location);
+    if let Some(parent) = location
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // not truncated, the content belongs to the owner
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&location)?;
+
+    Ok((file, location))
+}
+
+/// Lock `file` without blocking, `false` if another process holds it.
+fn try_lock(file: &File) -> std::io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+
+    let err = std::io::Error::last_os_error();
+    match err.kind() {
+        ErrorKind::WouldBlock => Ok(false),
+        _ => Err(err),
+    }
+}
+
+fn busy_error(db_location: &Path, file: &mut File) -> Box<dyn std::error::Error> {
+    let owner = match read_owner(file) {
+        owner if owner.is_empty() => String::new(),
+        owner => format!(" ({})", owner),
+    };
+
+    format!(
+        "another nubesync process{} is using {}, use --wait to wait for it",
+        owner,
+        db_location.display()
+    )
+    .into()
+}
+
+/// PID written by the owner of the lock, empty if unknown.
+fn read_owner(file: &mut File) -> String {
+    let mut content = String::new();
+    let _ = file
+        .rewind()
+        .and_then(|_| file.read_to_string(&mut content));
+
+    content.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn exclusive_until_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+
+        let lock = RunLock::acquire(&db, false).unwrap();
+        let err = RunLock::acquire(&db, false).unwrap_err().to_string();
+        assert!(err.contains(&std::process::id().to_string()), "{}", err);
+
+        drop(lock);
+        RunLock::acquire(&db, false).unwrap();
+    }
+
+    #[test]
+    fn left_lock_file_is_not_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        std::fs::write(dir.path().join("db.lock"), "999999").unwrap();
+
+        RunLock::acquire(&db, false).unwrap();
+        let content = std::fs::read_to_string(dir.path().join("db.lock")).unwrap();
+        assert_eq!(content, std::process::id().to_string());
+    }
+
+    #[test]
+    fn missing_dir_is_created() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("state/nubesync/db");
+
+        let lock = RunLock::acquire(&db, false).unwrap();
+        assert_eq!(lock.path(), dir.path().join("state/nubesync/db.lock"));
+        lock.remove().unwrap();
+        assert!(!dir.path().join("state/nubesync/db.lock").exists());
+    }
+
+    #[test]
+    fn wait_blocking() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let lock = RunLock::acquire(&db, false).unwrap();
+
+        let started = Instant::now();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+        RunLock::acquire(&db, true).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        release.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_in_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let lock = RunLock::acquire_async(&db, false).await.unwrap();
+        assert!(RunLock::acquire_async(&db, false).await.is_err());
+
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(lock);
+        });
+        RunLock::acquire_async(&db, true).await.unwrap();
+        release.await.unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::level_filters::LevelFilter;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+use crate::{progress, result::AppResult};
+
+/// Rotated log files kept besides the current one.
+const MAX_LOG_FILES: usize = 7;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Level of the nubesync logs, ignored if `RUST_LOG` is set.
+    pub level: LevelFilter,
+    pub format: LogFormat,
+    /// File rotated daily where the logs are written instead of stderr.
+    pub file: Option<PathBuf>,
+}
+
+/// Install the global subscriber. The returned guard flushes the log file when dropped,
+/// so it must live until the program ends.
+pub fn init(options: &LogOptions) -> AppResult<Option<WorkerGuard>> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("warn,nubesync={}", options.level)));
+
+    let (writer, guard, ansi) = match &options.file {
+        Some(file) => {
+            let prefix = file
+                .file_name()
+                .ok_or_else(|| format!("invalid log file: {}", file.display()))?;
+            let directory = match file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+
+            std::fs::create_dir_all(directory)?;
+
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(prefix.to_string_lossy())
+                .max_log_files(MAX_LOG_FILES)
+                .build(directory)?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard), false)
+        }
+        None => (
+            BoxMakeWriter::new(|| ProgressAwareStderr),
+            None,
+            std::io::stderr().is_terminal(),
+        ),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    let result = match options.format {
+        LogFormat::Text => builder.with_target(false).try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    result.map_err(|err| err.to_string())?;
+
+    Ok(guard)
+}
+
+/// Stderr clearing the progress bars before each log line, so they are redrawn below it.
+struct ProgressAwareStderr;
+
+impl Write for ProgressAwareStderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        progress::suspend(|| std::io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
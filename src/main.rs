@@ -1,17 +1,40 @@
-use std::path::PathBuf;
-
 use clap::Parser;
 use config::Config;
+use control::ControlClient;
+use daemon::Daemon;
+use lock::RunLock;
 use reqwest_dav::re_exports::tokio;
 
 use result::AppResult;
-use sync_service::SyncService;
+use store::{DbBackend, VersionStore};
+use sync_service::{Shutdown, SyncService};
+use tracing::info;
+use trash::Trash;
 
+mod archive;
+mod bandwidth;
+mod checksum;
 mod cli;
 mod config;
 mod conn_retry;
+mod control;
+mod daemon;
+mod dav;
+mod events;
+mod hooks;
+mod lock;
+mod logging;
+mod metrics;
+mod migrations;
+mod notifications;
+mod notify_push;
+mod progress;
+mod report;
 mod result;
+mod store;
 mod sync_service;
+mod systemd;
+mod trash;
 mod versions;
 
 #[cfg(feature = "version_migration")]
@@ -20,41 +43,216 @@ mod old_version;
 #[tokio::main]
 async fn main() {
     let cmd_options = cli::NubeSyncCommand::parse();
+    let _log_guard = logging::init(&cmd_options.log.options()).expect("Error starting logging");
 
     match cmd_options.cmd {
         cli::SubCommand::Sync(cmd) => sync(cmd).await,
-        cli::SubCommand::Clear(cmd) => clear(&cmd.out),
+        cli::SubCommand::Watch(cmd) => watch(cmd).await,
+        cli::SubCommand::Daemon(cmd) => daemon(cmd).await,
+        cli::SubCommand::Ctl(cmd) => ctl(cmd).await,
+        cli::SubCommand::Verify(cmd) => verify(cmd).await,
+        cli::SubCommand::Clear(cmd) => clear(cmd),
+        cli::SubCommand::Trash(cmd) => trash(cmd),
+        cli::SubCommand::Db(cmd) => db(cmd),
 
         #[cfg(feature = "version_migration")]
         cli::SubCommand::Migrate(cmd) => migrate(cmd).await,
     }
 }
 
-fn sync_service(config_path: PathBuf, out_dir: Option<&PathBuf>) -> AppResult<SyncService> {
-    let mut config = Config::load_from_file(config_path)?;
+async fn sync_service(args: &cli::RemoteArgs) -> AppResult<SyncService> {
+    let mut config = Config::load_from_file(args.config_location())?;
 
-    if let Some(out_dir) = out_dir {
+    if let Some(out_dir) = args.out_dir() {
         config.out_dir.clone_from(out_dir);
     }
 
-    SyncService::init(config)
+    let db_location = config.db_location(&args.remote_location())?;
+    let mut sync = SyncService::init(config, &db_location, args.wait()).await?;
+    if let Some(mode) = args.progress() {
+        sync.set_progress(mode);
+    }
+
+    Ok(sync)
+}
+
+/// Open the existing local db found with `args`. With `relocate`, a db left inside the out dir
+/// by older versions is moved to its location first, otherwise it is opened in place.
+fn open_db(args: &cli::DbLocationArgs, relocate: bool) -> AppResult<Box<dyn VersionStore>> {
+    let mut db_location = args.db_location()?;
+    if relocate {
+        store::relocate_legacy(&db_location, &args.out)?;
+    } else {
+        db_location = store::existing_location(&db_location, &args.out);
+    }
+
+    store::open_existing(&db_location)?
+        .ok_or_else(|| format!("local db not found in {}", db_location.display()).into())
 }
 
 async fn sync(cmd: cli::SyncSubCommand) {
-    let mut sync =
-        sync_service(cmd.config_location(), cmd.out_dir()).expect("Error starting sync service");
+    let mut sync = sync_service(&cmd.remote)
+        .await
+        .expect("Error starting sync service");
+    if let Some(report) = cmd.report_location() {
+        sync.set_report(report.clone());
+    }
 
-    sync.sync(&cmd.remote_location())
+    sync.sync(&cmd.remote.remote_location(), cmd.options())
         .await
         .expect("Error syncing");
 }
 
-fn clear(out_dir: &PathBuf) {
-    SyncService::clear_out_dir(out_dir).expect("Error clearing dir");
+async fn watch(cmd: cli::WatchSubCommand) {
+    let remote = &cmd.sync.remote;
+    let mut sync = sync_service(remote)
+        .await
+        .expect("Error starting sync service");
+    sync.set_shutdown(shutdown_on_ctrl_c());
+    if let Some(report) = cmd.sync.report_location() {
+        sync.set_report(report.clone());
+    }
+
+    sync.watch(
+        &remote.remote_location(),
+        cmd.sync.options(),
+        cmd.watch.options(),
+    )
+    .await
+    .expect("Error watching");
+}
+
+/// Stop gracefully on the first Ctrl-C, and right away on the second one.
+fn shutdown_on_ctrl_c() -> Shutdown {
+    let (stop, shutdown) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("finishing the current transfer, press Ctrl-C again to quit now");
+            let _ = stop.send(true);
+        }
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+
+    shutdown
+}
+
+async fn daemon(cmd: cli::DaemonSubCommand) {
+    Daemon::new(
+        cmd.config_location(),
+        cmd.watch.options(),
+        cmd.socket_location(),
+        cmd.metrics_address(),
+    )
+    .run()
+    .await
+    .expect("Error running daemon");
+}
+
+async fn ctl(cmd: cli::CtlSubCommand) {
+    let mut client = ControlClient::connect(&cmd.socket_location())
+        .await
+        .expect("Error connecting to daemon");
+
+    let (method, params) = cmd.cmd.request();
+    let result = client
+        .call(method, params)
+        .await
+        .expect("Error calling daemon");
+
+    if let cli::CtlCommand::Events = cmd.cmd {
+        // until the daemon stops
+        while let Ok(event) = client.next_message().await {
+            println!("{}", event["params"]);
+        }
+
+        return;
+    }
+
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+}
+
+async fn verify(cmd: cli::VerifySubCommand) {
+    let mut sync = sync_service(&cmd.remote)
+        .await
+        .expect("Error starting sync service");
+
+    sync.repair(&cmd.remote.remote_location(), cmd.verify_mode())
+        .await
+        .expect("Error verifying files");
+}
+
+fn clear(cmd: cli::ClearSubCommand) {
+    let db_location = cmd
+        .location
+        .db_location()
+        .expect("Error finding the local db");
+
+    SyncService::clear_out_dir(&cmd.location.out, &db_location, cmd.options())
+        .expect("Error clearing dir");
+}
+
+fn trash(cmd: cli::TrashSubCommand) {
+    match cmd {
+        cli::TrashSubCommand::List { out } => {
+            let entries = Trash::new(&out).list().expect("Error reading trash");
+            for entry in entries {
+                println!("{}\t{}", entry.batch, entry.path.display());
+            }
+        }
+        cli::TrashSubCommand::Restore { out, batch, path } => Trash::new(&out)
+            .restore(&batch, path.as_deref())
+            .expect("Error restoring from trash"),
+        cli::TrashSubCommand::Purge {
+            out,
+            older_than_days,
+        } => Trash::new(&out)
+            .purge(older_than_days.map(|days| chrono::Duration::days(days as i64)))
+            .expect("Error purging trash"),
+    }
+}
+
+fn db(cmd: cli::DbSubCommand) {
+    match cmd {
+        cli::DbSubCommand::Convert { location, to } => {
+            let db_location = location.db_location().expect("Error finding the local db");
+            let _lock = RunLock::acquire(&db_location, false).expect("Error locking local db");
+            let store = open_db(&location, true).expect("Error opening local db");
+            let db_location = store.location().to_path_buf();
+            let from = DbBackend::detect(&db_location)
+                .expect("Error reading local db")
+                .unwrap_or_default();
+            drop(store);
+
+            store::convert(&db_location, from, to).expect("Error converting local db");
+        }
+        cli::DbSubCommand::Show {
+            location,
+            href,
+            path,
+        } => {
+            let store = open_db(&location, false).expect("Error opening local db");
+            let entry = match (href, path) {
+                (Some(href), _) => store.get(&href).map(|file| file.map(|file| (href, file))),
+                (None, Some(path)) => store.find_by_path(&path),
+                (None, None) => unreachable!(),
+            }
+            .expect("Error reading local db");
+
+            match entry {
+                Some((href, file)) => {
+                    println!("{}\n{}", href, serde_json::to_string_pretty(&file).unwrap())
+                }
+                None => println!("entry not found"),
+            }
+        }
+    }
 }
 
 #[cfg(feature = "version_migration")]
-async fn migrate(cmd: cli::SyncSubCommand) {
+async fn migrate(cmd: cli::RemoteArgs) {
     let mut config =
         Config::load_from_file(cmd.config_location()).expect("Error loading config file");
 
@@ -62,9 +260,11 @@ async fn migrate(cmd: cli::SyncSubCommand) {
         config.out_dir.clone_from(out_dir);
     }
 
-    let mut sync = SyncService::init_with_empty_db(config).expect("Error starting sync service");
+    let mut sync = SyncService::init_with_empty_db(config, cmd.wait())
+        .await
+        .expect("Error starting sync service");
 
-    println!("Local db migration...");
+    info!("local db migration...");
 
     sync.migrate_db(&cmd.remote_location())
         .await
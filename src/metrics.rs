@@ -0,0 +1,362 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+use crate::{report::SyncReport, result::AppResult};
+
+/// Metrics of every sync in the process, exposed by the daemon with `--metrics-address`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Max size of the request head read from a scraper.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Time a scraper has to send the request head, so idle connections don't stay open.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pairs: Mutex<BTreeMap<String, Arc<PairMetrics>>>,
+    /// Requests attempted again by `ConnRetry` after failing.
+    pub retries: AtomicU64,
+    pub propfind_latency: Histogram,
+    pub get_latency: Histogram,
+}
+
+/// Totals of the syncs of a pair.
+#[derive(Debug, Default)]
+pub struct PairMetrics {
+    downloaded: AtomicU64,
+    deleted: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    /// Syncs that ended with an error.
+    sync_failures: AtomicU64,
+    /// Unix time of the last sync without errors, 0 if none.
+    last_success: AtomicI64,
+}
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    /// Metrics of the pair called `name`, kept across config reloads.
+    pub fn pair(&self, name: &str) -> Arc<PairMetrics> {
+        let mut pairs = self.pairs.lock().unwrap();
+        pairs.entry(name.to_string()).or_default().clone()
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let pairs = self.pairs.lock().unwrap().clone();
+        let mut out = String::new();
+
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_files_downloaded_total",
+                "Files downloaded.",
+                "counter",
+            ),
+            |pair| pair.downloaded.load(Ordering::Relaxed),
+        );
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_files_deleted_total",
+                "Local entries deleted.",
+                "counter",
+            ),
+            |pair| pair.deleted.load(Ordering::Relaxed),
+        );
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_files_failed_total",
+                "Entries that failed to sync.",
+                "counter",
+            ),
+            |pair| pair.failed.load(Ordering::Relaxed),
+        );
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_bytes_transferred_total",
+                "Bytes received from the server.",
+                "counter",
+            ),
+            |pair| pair.bytes.load(Ordering::Relaxed),
+        );
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_sync_failures_total",
+                "Syncs that ended with an error.",
+                "counter",
+            ),
+            |pair| pair.sync_failures.load(Ordering::Relaxed),
+        );
+        pair_metric(
+            &mut out,
+            &pairs,
+            (
+                "nubesync_last_success_timestamp_seconds",
+                "Unix time of the last sync without errors, 0 if none since the daemon started.",
+                "gauge",
+            ),
+            |pair| pair.last_success.load(Ordering::Relaxed).max(0) as u64,
+        );
+
+        let name = "nubesync_request_retries_total";
+        header(
+            &mut out,
+            name,
+            "Requests attempted again after failing.",
+            "counter",
+        );
+        let _ = writeln!(out, "{} {}", name, self.retries.load(Ordering::Relaxed));
+
+        let name = "nubesync_request_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "Latency of the requests to the server.",
+            "histogram",
+        );
+        self.propfind_latency.render(&mut out, name, "PROPFIND");
+        self.get_latency.render(&mut out, name, "GET");
+
+        out
+    }
+}
+
+impl PairMetrics {
+    /// Add the outcome of a finished sync.
+    pub fn record(&self, report: &SyncReport) {
+        let counts = &report.counts;
+        self.downloaded
+            .fetch_add(counts.downloaded as u64, Ordering::Relaxed);
+        self.deleted
+            .fetch_add(counts.deleted as u64, Ordering::Relaxed);
+        self.failed
+            .fetch_add(counts.failed as u64, Ordering::Relaxed);
+        self.bytes
+            .fetch_add(report.bytes_transferred, Ordering::Relaxed);
+
+        match (&report.error, report.finished) {
+            (Some(_), _) => {
+                self.sync_failures.fetch_add(1, Ordering::Relaxed);
+            }
+            (None, Some(finished)) => {
+                self.last_success
+                    .store(finished.timestamp(), Ordering::Relaxed);
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Run `future` observing how long it takes.
+    pub async fn time<F: Future>(&self, future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.await;
+        self.observe(started.elapsed());
+
+        output
+    }
+
+    fn render(&self, out: &mut String, name: &str, method: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                name,
+                method,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+            name, method, count
+        );
+        let _ = writeln!(out, "{}_sum{{method=\"{}\"}} {}", name, method, sum);
+        let _ = writeln!(out, "{}_count{{method=\"{}\"}} {}", name, method, count);
+    }
+}
+
+/// Answer `GET /metrics` requests in `listener`, each connection in its own local task.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!(%err, "error accepting metrics connection");
+                continue;
+            }
+        };
+
+        tokio::task::spawn_local(async move {
+            if let Err(err) = respond(stream).await {
+                warn!(%err, "error in metrics connection");
+            }
+        });
+    }
+}
+
+pub async fn bind(address: SocketAddr) -> AppResult<TcpListener> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|err| format!("can't listen for metrics in {}: {}", address, err).into())
+}
+
+async fn respond(mut stream: TcpStream) -> AppResult<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buffer))
+            .await
+            .map_err(|_| "timed out reading the metrics request")??;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", METRICS.render())
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Write the metric `(name, help, type)` with a sample for each pair.
+fn pair_metric(
+    out: &mut String,
+    pairs: &BTreeMap<String, Arc<PairMetrics>>,
+    (name, help, kind): (&str, &str, &str),
+    value: impl Fn(&PairMetrics) -> u64,
+) {
+    header(out, name, help, kind);
+    for (pair_name, pair) in pairs {
+        let _ = writeln!(
+            out,
+            "{}{{pair=\"{}\"}} {}",
+            name,
+            escape(pair_name),
+            value(pair)
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value of the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_samples_before_and_after_syncs() {
+        let metrics = Metrics::default();
+        let pair = metrics.pair("docs");
+
+        let out = metrics.render();
+        assert!(out.contains("nubesync_last_success_timestamp_seconds{pair=\"docs\"} 0\n"));
+        assert!(out.contains("nubesync_sync_failures_total{pair=\"docs\"} 0\n"));
+
+        let mut report = SyncReport::new("/docs");
+        report.fail("/docs/a", "denied");
+        report.finish(Some("1 entries failed to sync".to_string()));
+        pair.record(&report);
+
+        let out = metrics.render();
+        assert!(out.contains("nubesync_last_success_timestamp_seconds{pair=\"docs\"} 0\n"));
+        assert!(out.contains("nubesync_sync_failures_total{pair=\"docs\"} 1\n"));
+        assert!(out.contains("nubesync_files_failed_total{pair=\"docs\"} 1\n"));
+
+        let mut report = SyncReport::new("/docs");
+        report.finish(None);
+        pair.record(&report);
+
+        let finished = report.finished.unwrap().timestamp();
+        let out = metrics.render();
+        assert!(out.contains(&format!(
+            "nubesync_last_success_timestamp_seconds{{pair=\"docs\"}} {}\n",
+            finished
+        )));
+        assert!(out.contains("nubesync_sync_failures_total{pair=\"docs\"} 1\n"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+use serde_json::{json, Map, Value};
+use tracing::info;
+
+use crate::result::AppResult;
+
+/// Version of the JSON local db format written by this build.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Steps to upgrade a JSON db, the step at index `n` upgrades from version `n` to `n + 1`.
+const JSON_MIGRATIONS: [fn(Value) -> AppResult<Value>; SCHEMA_VERSION as usize] =
+    [paths_to_files, add_schema_version];
+
+/// Steps to upgrade a SQLite db, the step at index `n` upgrades from `user_version` `n` to `n + 1`.
+const SQLITE_MIGRATIONS: [&str; 2] = [
+    "CREATE TABLE IF NOT EXISTS files (
+        href TEXT PRIMARY KEY NOT NULL,
+        path TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS files_path ON files (path);",
+    "CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY NOT NULL,
+        value TEXT NOT NULL
+    );",
+];
+
+/// Upgrade the content of the JSON db in `location` to the current schema,
+/// keeping a copy of the old file. The flag tells if `db` was migrated.
+pub fn migrate_json(location: &Path, mut db: Value) -> AppResult<(Value, bool)> {
+    let version = json_schema_version(&db)?;
+    if version == SCHEMA_VERSION {
+        return Ok((db, false));
+    }
+
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "local db schema version {} is newer than the supported one ({}), update nubesync",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+
+    backup(location, version)?;
+    info!(
+        from = version,
+        to = SCHEMA_VERSION,
+        "migrating local db schema..."
+    );
+    for migration in &JSON_MIGRATIONS[version as usize..] {
+        db = migration(db)?;
+    }
+
+    Ok((db, true))
+}
+
+/// Upgrade the SQLite db to the current schema, keeping a copy of the old file if it had data.
+pub fn migrate_sqlite(location: &Path, connection: &mut Connection) -> AppResult<()> {
+    let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let target = SQLITE_MIGRATIONS.len() as u32;
+    if version == target {
+        return Ok(());
+    }
+
+    if version > target {
+        return Err(format!(
+            "local db schema version {} is newer than the supported one ({}), update nubesync",
+            version, target
+        )
+        .into());
+    }
+
+    if version > 0 {
+        connection.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        backup(location, version)?;
+    }
+
+    let transaction = connection.transaction()?;
+    for migration in &SQLITE_MIGRATIONS[version as usize..] {
+        transaction.execute_batch(migration)?;
+    }
+    transaction.pragma_update(None, "user_version", target)?;
+    transaction.commit()?;
+
+    Ok(())
+}
+
+fn json_schema_version(db: &Value) -> AppResult<u32> {
+    if let Some(version) = db.get("schema_version") {
+        let version = version
+            .as_u64()
+            .ok_or("invalid schema_version in local db")?;
+
+        return Ok(version as u32);
+    }
+
+    // formats written before the schema version existed
+    if db.get("paths").is_some() {
+        return Ok(0);
+    }
+
+    if db.get("files").is_some() {
+        return Ok(1);
+    }
+
+    Err("unknown local db format".into())
+}
+
+/// Copy the db in `location` to `<location>.v<version>.bak`.
+fn backup(location: &Path, version: u32) -> AppResult<()> {
+    let mut backup = location.as_os_str().to_os_string();
+    backup.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(backup);
+
+    info!(path = %backup.display(), "saving local db backup");
+    std::fs::copy(location, backup)?;
+
+    Ok(())
+}
+
+/// v0 only stored the local path of each href.
+fn paths_to_files(mut db: Value) -> AppResult<Value> {
+    let paths = match db.get_mut("paths").map(Value::take) {
+        Some(Value::Object(paths)) => paths,
+        _ => return Err("invalid paths in local db".into()),
+    };
+
+    let mut files = Map::new();
+    for (href, path) in paths {
+        let is_dir = path.as_str().is_some_and(|path| Path::new(path).is_dir());
+        // without last modification date the files are downloaded again in the next sync
+        files.insert(
+            href,
+            json!({
+                "path": path,
+                "is_dir": is_dir,
+                "last_modified": null,
+            }),
+        );
+    }
+
+    Ok(json!({ "files": files }))
+}
+
+/// v2 adds the `schema_version` field.
+fn add_schema_version(mut db: Value) -> AppResult<Value> {
+    db.as_object_mut()
+        .ok_or("invalid local db")?
+        .insert("schema_version".to_string(), json!(2));
+
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup_location(location: &Path, version: u32) -> PathBuf {
+        let mut backup = location.as_os_str().to_os_string();
+        backup.push(format!(".v{}.bak", version));
+        PathBuf::from(backup)
+    }
+
+    #[test]
+    fn migrate_json_from_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db.json");
+        std::fs::write(&location, "{}").unwrap();
+        let db = json!({ "paths": { "/dav/a.txt": "/out/a.txt" } });
+
+        let (db, migrated) = migrate_json(&location, db).unwrap();
+        assert!(migrated);
+        assert_eq!(db["schema_version"], json!(SCHEMA_VERSION));
+        assert_eq!(db["files"]["/dav/a.txt"]["path"], json!("/out/a.txt"));
+        assert_eq!(db["files"]["/dav/a.txt"]["last_modified"], Value::Null);
+        assert!(backup_location(&location, 0).is_file());
+    }
+
+    #[test]
+    fn migrate_json_without_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db.json");
+        std::fs::write(&location, "{}").unwrap();
+        let db = json!({ "files": {} });
+
+        let (db, migrated) = migrate_json(&location, db).unwrap();
+        assert!(migrated);
+        assert_eq!(db["schema_version"], json!(SCHEMA_VERSION));
+        assert!(backup_location(&location, 1).is_file());
+    }
+
+    #[test]
+    fn current_json_is_untouched() {
+        let db = json!({ "schema_version": SCHEMA_VERSION, "files": {} });
+        let (migrated_db, migrated) = migrate_json(Path::new("/nonexistent"), db.clone()).unwrap();
+
+        assert!(!migrated);
+        assert_eq!(migrated_db, db);
+    }
+
+    #[test]
+    fn reject_newer_json() {
+        let db = json!({ "schema_version": SCHEMA_VERSION + 1, "files": {} });
+        assert!(migrate_json(Path::new("/nonexistent"), db).is_err());
+        assert!(migrate_json(Path::new("/nonexistent"), json!({})).is_err());
+    }
+
+    fn user_version(connection: &Connection) -> u32 {
+        connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn create_sqlite_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db.sqlite");
+        let mut connection = Connection::open(&location).unwrap();
+
+        migrate_sqlite(&location, &mut connection).unwrap();
+        assert_eq!(user_version(&connection), SQLITE_MIGRATIONS.len() as u32);
+        connection
+            .execute_batch("SELECT * FROM files; SELECT * FROM meta;")
+            .unwrap();
+        // nothing to keep in a new db
+        assert!(!backup_location(&location, 0).exists());
+    }
+
+    #[test]
+    fn migrate_sqlite_step_by_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db.sqlite");
+        let mut connection = Connection::open(&location).unwrap();
+        connection.execute_batch(SQLITE_MIGRATIONS[0]).unwrap();
+        connection.pragma_update(None, "user_version", 1).unwrap();
+        connection
+            .execute(
+                "INSERT INTO files (href, path, data) VALUES ('/dav/a.txt', '/out/a.txt', '{}')",
+                [],
+            )
+            .unwrap();
+
+        migrate_sqlite(&location, &mut connection).unwrap();
+        assert_eq!(user_version(&connection), 2);
+        let files: u32 = connection
+            .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(files, 1);
+        connection.execute_batch("SELECT * FROM meta").unwrap();
+        assert!(backup_location(&location, 1).is_file());
+    }
+
+    #[test]
+    fn reject_newer_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db.sqlite");
+        let mut connection = Connection::open(&location).unwrap();
+        connection
+            .pragma_update(None, "user_version", SQLITE_MIGRATIONS.len() + 1)
+            .unwrap();
+
+        assert!(migrate_sqlite(&location, &mut connection).is_err());
+    }
+}
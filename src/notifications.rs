@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::process::Command;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{report::SyncReport, result::AppResult};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and when the outcome of the syncs is notified.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notifications {
+    /// URL receiving a JSON POST with the summary of the sync.
+    #[serde(default)]
+    pub webhook: Option<Url>,
+    /// Show freedesktop notifications with `notify-send`.
+    #[serde(default)]
+    pub desktop: bool,
+    /// Outcomes notified.
+    #[serde(default = "default_notify_on")]
+    pub on: Vec<Outcome>,
+    /// Min seconds between notifications of the same outcome, the last skipped one is sent when it ends.
+    #[serde(default = "default_min_interval_secs")]
+    pub min_interval_secs: u64,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            webhook: None,
+            desktop: false,
+            on: default_notify_on(),
+            min_interval_secs: default_min_interval_secs(),
+        }
+    }
+}
+
+fn default_notify_on() -> Vec<Outcome> {
+    vec![Outcome::Failure, Outcome::Conflict]
+}
+
+fn default_min_interval_secs() -> u64 {
+    300
+}
+
+/// Outcome of a sync, from the most to the least severe.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The sync failed.
+    Failure,
+    /// Local changes were overwritten by the server version.
+    Conflict,
+    /// The sync downloaded or deleted entries.
+    Completion,
+}
+
+impl Outcome {
+    fn of(report: &SyncReport) -> Option<Self> {
+        if report.error.is_some() {
+            Some(Outcome::Failure)
+        } else if !report.conflicts.is_empty() {
+            Some(Outcome::Conflict)
+        } else if report.counts.downloaded + report.counts.deleted > 0 {
+            Some(Outcome::Completion)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sends the notifications of the sync services, at most one per webhook, outcome and interval.
+/// Clones share the same rate limit.
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    limits: Arc<Mutex<HashMap<LimitKey, Limit>>>,
+}
+
+type LimitKey = (Option<Url>, Outcome);
+
+#[derive(Debug)]
+struct Limit {
+    last_sent: Instant,
+    suppressed: usize,
+    /// Last notification skipped, sent when the interval ends.
+    pending: Option<Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    config: Notifications,
+    client: reqwest::Client,
+    out_dir: PathBuf,
+    report: SyncReport,
+}
+
+impl Notifier {
+    /// Notify the outcome of the sync in `report`, if enabled in `config`.
+    /// Errors are logged, they don't fail the sync.
+    pub async fn notify(
+        &self,
+        config: &Notifications,
+        client: &reqwest::Client,
+        out_dir: &Path,
+        report: &SyncReport,
+    ) {
+        let Some(outcome) = Outcome::of(report).filter(|outcome| config.on.contains(outcome))
+        else {
+            return;
+        };
+
+        if config.webhook.is_none() && !config.desktop {
+            return;
+        }
+
+        let key = (config.webhook.clone(), outcome);
+        let min_interval = Duration::from_secs(config.min_interval_secs);
+        let suppressed = {
+            let mut limits = self.limits.lock().unwrap();
+            if let Some(limit) = limits.get_mut(&key) {
+                // read once, the interval can end while the report is cloned
+                let elapsed = limit.last_sent.elapsed();
+                if elapsed < min_interval {
+                    debug!(?outcome, "notification skipped by the rate limit");
+                    limit.suppressed += 1;
+                    let pending = Pending {
+                        config: config.clone(),
+                        client: client.clone(),
+                        out_dir: out_dir.to_path_buf(),
+                        report: report.clone(),
+                    };
+                    if limit.pending.replace(pending).is_none() {
+                        tokio::spawn(self.clone().flush(key, min_interval - elapsed));
+                    }
+                    return;
+                }
+            }
+
+            let previous = limits.insert(
+                key,
+                Limit {
+                    last_sent: Instant::now(),
+                    suppressed: 0,
+                    pending: None,
+                },
+            );
+            previous.map_or(0, |limit| limit.suppressed)
+        };
+
+        send(config, client, outcome, out_dir, report, suppressed).await;
+    }
+
+    /// Send the last notification skipped for `key` once its interval ends, in `remaining`.
+    async fn flush(self, key: LimitKey, remaining: Duration) {
+        tokio::time::sleep(remaining).await;
+        let (pending, suppressed) = {
+            let mut limits = self.limits.lock().unwrap();
+            let Some(limit) = limits.get_mut(&key) else {
+                return;
+            };
+            let Some(pending) = limit.pending.take() else {
+                return;
+            };
+            // the pending one is sent, the others are counted in it
+            let suppressed = std::mem::take(&mut limit.suppressed) - 1;
+            limit.last_sent = Instant::now();
+            (pending, suppressed)
+        };
+
+        let Pending {
+            config,
+            client,
+            out_dir,
+            report,
+        } = pending;
+        send(&config, &client, key.1, &out_dir, &report, suppressed).await;
+    }
+}
+
+async fn send(
+    config: &Notifications,
+    client: &reqwest::Client,
+    outcome: Outcome,
+    out_dir: &Path,
+    report: &SyncReport,
+    suppressed: usize,
+) {
+    let summary = summary(outcome, report, suppressed);
+    if let Some(webhook) = &config.webhook {
+        let payload = json!({
+            "text": summary,
+            "outcome": outcome,
+            "remote": report.remote,
+            "out_dir": out_dir,
+            "started": report.started,
+            "finished": report.finished,
+            "error": report.error,
+            "bytes_transferred": report.bytes_transferred,
+            "counts": report.counts,
+            "conflicts": report.conflicts,
+            "suppressed": suppressed,
+        });
+        if let Err(err) = post_webhook(client, webhook, &payload).await {
+            warn!(%err, "error sending webhook notification");
+        }
+    }
+
+    if config.desktop {
+        if let Err(err) = show_desktop(outcome, &summary).await {
+            warn!(%err, "error showing desktop notification");
+        }
+    }
+}
+
+fn summary(outcome: Outcome, report: &SyncReport, suppressed: usize) -> String {
+    let mut summary = match outcome {
+        Outcome::Failure => format!(
+            "sync of {} failed: {}",
+            report.remote,
+            report.error.as_deref().unwrap_or_default()
+        ),
+        Outcome::Conflict => format!(
+            "sync of {} overwrote {} locally changed files",
+            report.remote,
+            report.conflicts.len()
+        ),
+        Outcome::Completion => format!(
+            "sync of {} finished: {} downloaded, {} deleted",
+            report.remote, report.counts.downloaded, report.counts.deleted
+        ),
+    };
+
+    if suppressed > 0 {
+        summary.push_str(&format!(" ({} similar notifications skipped)", suppressed));
+    }
+
+    summary
+}
+
+async fn post_webhook(
+    client: &reqwest::Client,
+    webhook: &Url,
+    payload: &serde_json::Value,
+) -> AppResult<()> {
+    client
+        .post(webhook.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(payload)?)
+        .timeout(WEBHOOK_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+async fn show_desktop(outcome: Outcome, summary: &str) -> AppResult<()> {
+    let (title, urgency) = match outcome {
+        Outcome::Failure => ("nubesync: sync failed", "critical"),
+        Outcome::Conflict => ("nubesync: local changes overwritten", "normal"),
+        Outcome::Completion => ("nubesync: sync finished", "low"),
+    };
+
+    let status = Command::new("notify-send")
+        .arg("--app-name=nubesync")
+        .arg(format!("--urgency={}", urgency))
+        .arg(title)
+        .arg(summary)
+        .status()
+        .await
+        .map_err(|err| format!("can't run notify-send: {}", err))?;
+
+    if !status.success() {
+        return Err(format!("notify-send failed: {}", status).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Webhook answering every POST, with the payloads received in the channel.
+    async fn serve_webhook() -> (Url, mpsc::UnboundedReceiver<serde_json::Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        let (sender, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                let body = loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .and_then(|length| length.parse::<usize>().ok())
+                            .unwrap_or_default();
+                        if body.len() >= length {
+                            break body.to_string();
+                        }
+                    }
+                };
+                sender.send(serde_json::from_str(&body).unwrap()).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    fn failed_report(error: &str) -> SyncReport {
+        let mut report = SyncReport::new("/docs");
+        report.finish(Some(error.to_string()));
+        report
+    }
+
+    #[tokio::test]
+    async fn skipped_notifications_are_flushed() {
+        let (webhook, mut received) = serve_webhook().await;
+        let config = Notifications {
+            webhook: Some(webhook),
+            min_interval_secs: 1,
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let out_dir = Path::new("/tmp/docs");
+
+        // clones share the rate limit, like the pairs of the daemon
+        let notifier = Notifier::default();
+        notifier
+            .notify(&config, &client, out_dir, &failed_report("first"))
+            .await;
+        for error in ["second", "third"] {
+            notifier
+                .clone()
+                .notify(&config, &client, out_dir, &failed_report(error))
+                .await;
+        }
+
+        let first = received.recv().await.unwrap();
+        assert_eq!(first["error"], "first");
+        assert_eq!(first["suppressed"], 0);
+
+        let started = Instant::now();
+        let flushed = received.recv().await.unwrap();
+        assert_eq!(flushed["error"], "third");
+        assert_eq!(flushed["suppressed"], 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(received.try_recv().is_err());
+    }
+}
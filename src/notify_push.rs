@@ -0,0 +1,405 @@
+use futures_util::{SinkExt, StreamExt};
+use reqwest_dav::Client;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::{config::Config, result::AppResult};
+
+const CAPABILITIES_PATH: &str = "ocs/v2.php/cloud/capabilities?format=json";
+
+/// Connection to the Nextcloud `notify_push` app, which sends an event when files change.
+pub struct NotifyPush {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl NotifyPush {
+    /// Find the websocket endpoint in the server capabilities, `None` if `notify_push` is not installed.
+    pub async fn discover(client: &Client, config: &Config) -> AppResult<Option<String>> {
+        let response = client
+            .agent
+            .get(config.host.join(CAPABILITIES_PATH)?)
+            .basic_auth(&config.username, Some(&config.password))
+            .header("OCS-APIRequest", "true")
+            .send()
+            .await?
+            .error_for_status()?;
+        let capabilities: Value = serde_json::from_str(&response.text().await?)?;
+
+        let endpoint = capabilities
+            .pointer("/ocs/data/capabilities/notify_push/endpoints/websocket")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        Ok(endpoint)
+    }
+
+    /// Open the websocket in `endpoint` and log in.
+    pub async fn connect(endpoint: &str, config: &Config) -> AppResult<Self> {
+        let (mut socket, _) = tokio_tungstenite::connect_async(endpoint).await?;
+        socket.send(Message::Text(config.username.clone())).await?;
+        socket.send(Message::Text(config.password.clone())).await?;
+
+        let mut push = Self { socket };
+        match push.next_message().await?.as_str() {
+            "authenticated" => Ok(push),
+            message => Err(format!("notify_push authentication failed: {}", message).into()),
+        }
+    }
+
+    /// Wait until the server notifies a change in the files of the user.
+    pub async fn file_changed(&mut self) -> AppResult<()> {
+        loop {
+            let message = self.next_message().await?;
+            if message == "notify_file" {
+                return Ok(());
+            }
+
+            if let Some(err) = message.strip_prefix("err: ") {
+                return Err(format!("notify_push error: {}", err).into());
+            }
+        }
+    }
+
+    /// Next text message, pings are answered while reading.
+    async fn next_message(&mut self) -> AppResult<String> {
+        loop {
+            let message = self
+                .socket
+                .next()
+                .await
+                .ok_or("notify_push connection closed")??;
+
+            match message {
+                Message::Text(text) => return Ok(text),
+                Message::Close(_) => return Err("notify_push connection closed".into()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use clap::Parser;
+    use reqwest_dav::{Auth, ClientBuilder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{broadcast, watch},
+    };
+    use url::Url;
+
+    use super::*;
+    use crate::{
+        cli::WatchArgs,
+        events::{Event, EventKind, EventSink},
+        sync_service::{SyncOptions, SyncService, WatchOptions},
+    };
+
+    const USERNAME: &str = "user";
+    const PASSWORD: &str = "secret";
+    const REMOTE: &str = "remote.php/dav/files/user/docs/";
+
+    /// What the mock `notify_push` does after a successful login.
+    #[derive(Debug, Copy, Clone)]
+    enum Push {
+        /// Report a change once and keep the connection open.
+        NotifyOnce,
+        /// Close the connection.
+        Drop,
+    }
+
+    /// Websocket server checking the login, returns its endpoint and a counter of connections.
+    async fn serve_push(push: Push) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}/push/ws", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let username = socket.next().await.unwrap().unwrap();
+                    let password = socket.next().await.unwrap().unwrap();
+                    if username != Message::Text(USERNAME.to_string())
+                        || password != Message::Text(PASSWORD.to_string())
+                    {
+                        let error = Message::Text("err: Invalid credentials".to_string());
+                        let _ = socket.send(error).await;
+                        return;
+                    }
+
+                    socket
+                        .send(Message::Text("authenticated".to_string()))
+                        .await
+                        .unwrap();
+                    if let Push::NotifyOnce = push {
+                        for message in ["notify_activity", "notify_file"] {
+                            let _ = socket.send(Message::Text(message.to_string())).await;
+                        }
+                        while let Some(Ok(_)) = socket.next().await {}
+                    }
+                });
+            }
+        });
+
+        (endpoint, connections)
+    }
+
+    /// DAV server with an empty remote folder, and the `notify_push` `endpoint` in its capabilities.
+    async fn serve_dav(endpoint: Option<String>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let endpoint = endpoint.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 4096];
+                    let head_end = loop {
+                        let read = stream.read(&mut buffer).await.unwrap();
+                        request.extend_from_slice(&buffer[..read]);
+                        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break end + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+                    let content_length = head
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                    while request.len() < head_end + content_length {
+                        let read = stream.read(&mut buffer).await.unwrap();
+                        request.extend_from_slice(&buffer[..read]);
+                    }
+
+                    let mut request_line = head.split_whitespace();
+                    let (method, path) =
+                        (request_line.next().unwrap(), request_line.next().unwrap());
+                    let (status, body) = match method {
+                        "GET" => {
+                            let push = match endpoint {
+                                Some(endpoint) => json_endpoint(&endpoint),
+                                None => String::new(),
+                            };
+                            (
+                                "200 OK",
+                                format!(r#"{{"ocs":{{"data":{{"capabilities":{{{}}}}}}}}}"#, push),
+                            )
+                        }
+                        "PROPFIND" => ("207 Multi-Status", folder_listing(path)),
+                        _ => ("405 Method Not Allowed", String::new()),
+                    };
+
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+
+        host
+    }
+
+    fn json_endpoint(endpoint: &str) -> String {
+        format!(
+            r#""notify_push":{{"endpoints":{{"websocket":"{}"}}}}"#,
+            endpoint
+        )
+    }
+
+    fn folder_listing(href: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+            <d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns"><d:response>
+                <d:href>{}</d:href>
+                <d:propstat><d:prop>
+                    <d:getlastmodified>Mon, 01 Jan 2024 00:00:00 GMT</d:getlastmodified>
+                    <d:resourcetype><d:collection/></d:resourcetype>
+                    <d:getetag>"e1"</d:getetag>
+                </d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+            </d:response></d:multistatus>"#,
+            href
+        )
+    }
+
+    fn config(host: &Url, password: &str, out_dir: &Path) -> Config {
+        let config = format!(
+            r#"
+            host = "{}"
+            username = "{}"
+            password = "{}"
+            out_dir = "{}"
+            black_list = []
+            "#,
+            host,
+            USERNAME,
+            password,
+            out_dir.display()
+        );
+
+        toml::from_str(&config).unwrap()
+    }
+
+    fn client(host: &Url) -> Client {
+        ClientBuilder::new()
+            .set_host(host.to_string())
+            .set_auth(Auth::Basic(USERNAME.to_string(), PASSWORD.to_string()))
+            .build()
+            .unwrap()
+    }
+
+    /// Watch the remote folder of `host` until `syncs` finish or `timeout` passes, returning how
+    /// many finished.
+    async fn count_syncs(host: Url, push: bool, syncs: usize, timeout: Duration) -> usize {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&host, PASSWORD, &dir.path().join("out"));
+        let mut sync = SyncService::init(config, &dir.path().join("db"), false)
+            .await
+            .unwrap();
+        let (sender, mut events) = broadcast::channel(16);
+        sync.set_events(EventSink::new("test", sender));
+        let (stop, shutdown) = watch::channel(false);
+        sync.set_shutdown(shutdown);
+
+        let options = WatchOptions {
+            interval: Duration::from_secs(3600),
+            max_backoff: Duration::from_secs(3600),
+            push,
+        };
+        let counter = async {
+            let mut finished = 0;
+            let _ = tokio::time::timeout(timeout, async {
+                while finished < syncs {
+                    if let Ok(Event {
+                        kind: EventKind::SyncFinished { error },
+                        ..
+                    }) = events.recv().await
+                    {
+                        assert_eq!(error, None);
+                        finished += 1;
+                    }
+                }
+            })
+            .await;
+            stop.send(true).unwrap();
+
+            finished
+        };
+
+        let (result, finished) =
+            tokio::join!(sync.watch(REMOTE, SyncOptions::default(), options), counter);
+        result.unwrap();
+
+        finished
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let (endpoint, _) = serve_push(Push::Drop).await;
+        let host = Url::parse("http://127.0.0.1/").unwrap();
+
+        NotifyPush::connect(&endpoint, &config(&host, PASSWORD, Path::new("out")))
+            .await
+            .unwrap();
+        let err = NotifyPush::connect(&endpoint, &config(&host, "wrong", Path::new("out")))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("authentication failed"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn file_changes() {
+        let host = Url::parse("http://127.0.0.1/").unwrap();
+        let config = config(&host, PASSWORD, Path::new("out"));
+
+        let (endpoint, _) = serve_push(Push::NotifyOnce).await;
+        let mut push = NotifyPush::connect(&endpoint, &config).await.unwrap();
+        push.file_changed().await.unwrap();
+
+        let (endpoint, _) = serve_push(Push::Drop).await;
+        let mut push = NotifyPush::connect(&endpoint, &config).await.unwrap();
+        assert!(push.file_changed().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn discover_endpoint() {
+        let endpoint = "ws://127.0.0.1:1/push/ws".to_string();
+        let host = serve_dav(Some(endpoint.clone())).await;
+        let found =
+            NotifyPush::discover(&client(&host), &config(&host, PASSWORD, Path::new("out")))
+                .await
+                .unwrap();
+        assert_eq!(found, Some(endpoint));
+
+        let host = serve_dav(None).await;
+        let found =
+            NotifyPush::discover(&client(&host), &config(&host, PASSWORD, Path::new("out")))
+                .await
+                .unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[tokio::test]
+    async fn change_triggers_sync() {
+        let (endpoint, connections) = serve_push(Push::NotifyOnce).await;
+        let host = serve_dav(Some(endpoint)).await;
+
+        let syncs = count_syncs(host, true, 2, Duration::from_secs(10)).await;
+        assert_eq!(syncs, 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn poll_without_capability() {
+        let host = serve_dav(None).await;
+
+        let syncs = count_syncs(host, true, 2, Duration::from_secs(1)).await;
+        assert_eq!(syncs, 1);
+    }
+
+    #[tokio::test]
+    async fn poll_after_connection_drop() {
+        let (endpoint, connections) = serve_push(Push::Drop).await;
+        let host = serve_dav(Some(endpoint)).await;
+
+        // no sync and no reconnection until the next interval
+        let syncs = count_syncs(host, true, 2, Duration::from_secs(1)).await;
+        assert_eq!(syncs, 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn no_push_disables_it() {
+        let args = WatchArgs::try_parse_from(["watch", "--no-push"]).unwrap();
+        assert!(!args.options().push);
+
+        let (endpoint, connections) = serve_push(Push::NotifyOnce).await;
+        let host = serve_dav(Some(endpoint)).await;
+
+        let syncs = count_syncs(host, args.options().push, 2, Duration::from_secs(1)).await;
+        assert_eq!(syncs, 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 0);
+    }
+}
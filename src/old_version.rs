@@ -4,10 +4,16 @@ use reqwest_dav::{list_cmd::ListEntity, Auth, ClientBuilder, Depth};
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    bandwidth::BandwidthLimiter,
     config::Config,
+    lock::RunLock,
+    migrations::SCHEMA_VERSION,
+    notifications::Notifier,
+    report::SyncReport,
     result::AppResult,
+    store::DbBackend,
     sync_service::{SyncService, _SyncService},
-    versions::{self, Href, LocalFile, _LocalVersion},
+    versions::{self, Href, LocalFile, Origin, _LocalVersion},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,7 +42,7 @@ impl LocalVersion {
 }
 
 impl SyncService {
-    pub fn init_with_empty_db(config: Config) -> AppResult<SyncService> {
+    pub async fn init_with_empty_db(config: Config, wait: bool) -> AppResult<SyncService> {
         let reqwest_client = reqwest::ClientBuilder::new().use_rustls_tls().build()?;
         let client = ClientBuilder::new()
             .set_agent(reqwest_client)
@@ -48,13 +54,32 @@ impl SyncService {
             .build()?;
 
         let local_version = versions::LocalVersion::from(_LocalVersion {
+            schema_version: SCHEMA_VERSION,
+            origin: None,
             files: HashMap::new(),
         });
 
+        let db_location = config.out_dir.join(".sync");
+        let lock = RunLock::acquire_async(&db_location, wait).await?;
+        let store = DbBackend::Json.open(db_location)?;
         let service = SyncService::from(_SyncService {
             client,
             local_version,
+            store,
+            bandwidth: BandwidthLimiter::new(
+                config.bandwidth_limit,
+                config.bandwidth_schedule.clone(),
+            ),
             config,
+            _lock: lock,
+            shutdown: None,
+            events: None,
+            control: None,
+            progress: None,
+            report: SyncReport::default(),
+            report_location: None,
+            metrics: None,
+            notifier: Notifier::default(),
         });
 
         Ok(service)
@@ -79,6 +104,10 @@ impl SyncService {
                         path: paths.local,
                         is_dir: false,
                         last_modified: Some(file.last_modified),
+                        local_modified: None,
+                        size: None,
+                        checksum: None,
+                        file_id: None,
                     };
 
                     (file.href.clone(), local)
@@ -94,6 +123,10 @@ impl SyncService {
                         path: paths.local,
                         is_dir: true,
                         last_modified: None,
+                        local_modified: None,
+                        size: None,
+                        checksum: None,
+                        file_id: None,
                     };
 
                     (folder.href.clone(), local)
@@ -104,8 +137,13 @@ impl SyncService {
         }
 
         versions::LocalVersion::from(_LocalVersion {
+            schema_version: SCHEMA_VERSION,
+            origin: Some(Origin {
+                host: self.config().host.to_string(),
+                remote_dir: remote_dir.to_string(),
+            }),
             files: new_version_files,
         })
-        .save_in_file(&self.config().out_dir)
+        .save_in_file(&self.config().out_dir.join(".sync"))
     }
 }
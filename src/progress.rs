@@ -0,0 +1,157 @@
+use std::{
+    io::IsTerminal,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+
+/// Time between progress lines when stdout is not a terminal.
+const LINE_INTERVAL: Duration = Duration::from_secs(10);
+
+const TOTAL_TEMPLATE: &str = "[{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}, ETA {eta}, {msg}";
+const FILE_TEMPLATE: &str = "  {wide_msg} {bytes}/{total_bytes}";
+
+/// Bars drawn in the terminal, shared with the logger so log lines don't break them.
+static BARS: LazyLock<MultiProgress> =
+    LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::stdout()));
+
+/// Run `f` with the bars cleared, to write other output in the terminal.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    BARS.suspend(f)
+}
+
+/// How the progress of the downloads is shown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// Bars for the whole sync and the current file, redrawn in place.
+    Bars,
+    /// A line every few seconds, for logs and pipes.
+    Lines,
+}
+
+impl ProgressMode {
+    /// Bars if stdout is a terminal, lines otherwise.
+    pub fn detect() -> Self {
+        if std::io::stdout().is_terminal() {
+            ProgressMode::Bars
+        } else {
+            ProgressMode::Lines
+        }
+    }
+}
+
+/// Bytes and files downloaded in a sync, shown according to its mode.
+/// Without mode the progress is tracked but not shown.
+pub struct SyncProgress {
+    mode: Option<ProgressMode>,
+    total: ProgressBar,
+    file: ProgressBar,
+    files: u64,
+    done_files: u64,
+    last_line: Instant,
+}
+
+impl SyncProgress {
+    pub fn new(mode: Option<ProgressMode>, files: u64, bytes: u64) -> Self {
+        let (total, file) = match mode {
+            Some(ProgressMode::Bars) if files > 0 => {
+                let total = BARS.add(ProgressBar::new(bytes));
+                total.set_style(style(TOTAL_TEMPLATE));
+                let file = BARS.add(ProgressBar::new(0));
+                file.set_style(style(FILE_TEMPLATE));
+                (total, file)
+            }
+            // hidden bars still measure the rate
+            _ => (
+                ProgressBar::with_draw_target(Some(bytes), ProgressDrawTarget::hidden()),
+                ProgressBar::hidden(),
+            ),
+        };
+
+        let progress = Self {
+            mode,
+            total,
+            file,
+            files,
+            done_files: 0,
+            last_line: Instant::now(),
+        };
+        progress.total.set_message(progress.files_left());
+
+        progress
+    }
+
+    /// Start the download of `name`, with `bytes` according to the server.
+    pub fn start_file(&mut self, name: &str, bytes: u64) {
+        self.file.set_length(bytes);
+        self.file.set_position(0);
+        self.file.set_message(name.to_string());
+    }
+
+    /// Discard the bytes of the current file, to download it again.
+    pub fn restart_file(&mut self) {
+        let downloaded = self.file.position();
+        self.total
+            .set_position(self.total.position().saturating_sub(downloaded));
+        self.file.set_position(0);
+    }
+
+    pub fn advance(&mut self, bytes: usize) {
+        self.file.inc(bytes as u64);
+        self.total.inc(bytes as u64);
+
+        if self.mode == Some(ProgressMode::Lines) && self.last_line.elapsed() >= LINE_INTERVAL {
+            self.print_line();
+        }
+    }
+
+    pub fn finish_file(&mut self) {
+        self.done_files += 1;
+        self.total.set_message(self.files_left());
+    }
+
+    /// Print the final line when showing lines.
+    pub fn finish(&mut self) {
+        if self.mode == Some(ProgressMode::Lines) && self.files > 0 {
+            self.print_line();
+        }
+    }
+
+    fn print_line(&mut self) {
+        self.last_line = Instant::now();
+        println!(
+            "progress: {}/{} at {}/s, ETA {}, {}",
+            HumanBytes(self.total.position()),
+            HumanBytes(self.total.length().unwrap_or_default()),
+            HumanBytes(self.total.per_sec() as u64),
+            HumanDuration(self.total.eta()),
+            self.files_left()
+        );
+    }
+
+    fn files_left(&self) -> String {
+        format!(
+            "{} of {} files left",
+            self.files - self.done_files,
+            self.files
+        )
+    }
+}
+
+impl Drop for SyncProgress {
+    fn drop(&mut self) {
+        for bar in [&self.total, &self.file] {
+            bar.finish_and_clear();
+            BARS.remove(bar);
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("invalid progress template")
+        .progress_chars("=> ")
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{result::AppResult, versions::Href};
+
+/// Outcome of a sync, written as JSON with `--report`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    pub remote: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Error that ended the sync, if it failed.
+    pub error: Option<String>,
+    /// Bytes received from the server, including the discarded downloads.
+    pub bytes_transferred: u64,
+    pub counts: ReportCounts,
+    pub downloaded: Vec<ReportEntry>,
+    pub created: Vec<ReportEntry>,
+    pub deleted: Vec<ReportEntry>,
+    pub skipped: Vec<ReportEntry>,
+    pub failed: Vec<ReportEntry>,
+    /// Downloaded files whose local changes were overwritten.
+    pub conflicts: Vec<ReportEntry>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReportCounts {
+    pub downloaded: usize,
+    pub created: usize,
+    pub deleted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub conflicts: usize,
+}
+
+/// Entry of the report, with the skip reason or the error if it was not synced.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    pub href: Href,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ReportEntry {
+    pub fn new(href: &str) -> Self {
+        Self {
+            href: href.to_string(),
+            path: None,
+            bytes: None,
+            reason: None,
+            error: None,
+        }
+    }
+
+    pub fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+impl SyncReport {
+    pub fn new(remote: &str) -> Self {
+        Self {
+            remote: remote.to_string(),
+            started: Utc::now(),
+            ..Default::default()
+        }
+    }
+
+    pub fn skip(&mut self, href: &str, reason: &str) {
+        let mut entry = ReportEntry::new(href);
+        entry.reason = Some(reason.to_string());
+        self.skipped.push(entry);
+    }
+
+    pub fn conflict(&mut self, href: &str, path: &Path, reason: &str) {
+        let mut entry = ReportEntry::new(href).with_path(path);
+        entry.reason = Some(reason.to_string());
+        self.conflicts.push(entry);
+    }
+
+    pub fn fail(&mut self, href: &str, error: &str) {
+        let mut entry = ReportEntry::new(href);
+        entry.error = Some(error.to_string());
+        self.failed.push(entry);
+    }
+
+    /// Set the end time and the counts, with the error of the sync if it failed.
+    pub fn finish(&mut self, error: Option<String>) {
+        self.finished = Some(Utc::now());
+        self.error = error;
+        self.counts = ReportCounts {
+            downloaded: self.downloaded.len(),
+            created: self.created.len(),
+            deleted: self.deleted.len(),
+            skipped: self.skipped.len(),
+            failed: self.failed.len(),
+            conflicts: self.conflicts.len(),
+        };
+    }
+
+    pub fn save_in_file(&self, location: &Path) -> AppResult<()> {
+        let mut file = File::create(location)?;
+        let json_report = serde_json::to_string_pretty(&self)?;
+
+        file.write_all(json_report.as_bytes())?;
+
+        Ok(())
+    }
+}
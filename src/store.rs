@@ -0,0 +1,575 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{
+    migrations,
+    result::AppResult,
+    versions::{Href, LocalFile, LocalVersion, Origin},
+};
+
+/// Storage of the local version database.
+///
+/// `put` and `delete` are durable right away in transactional backends,
+/// the others keep the changes in memory until `flush`.
+pub trait VersionStore: Send {
+    /// Read every entry.
+    fn load(&self) -> AppResult<LocalVersion>;
+
+    fn get(&self, href: &Href) -> AppResult<Option<LocalFile>>;
+
+    /// Search the entry synced to the local `path`.
+    fn find_by_path(&self, path: &Path) -> AppResult<Option<(Href, LocalFile)>>;
+
+    /// Insert or replace an entry.
+    fn put(&mut self, href: &Href, file: &LocalFile) -> AppResult<()>;
+
+    fn delete(&mut self, href: &Href) -> AppResult<()>;
+
+    /// Record the server and remote location the entries belong to.
+    fn set_origin(&mut self, origin: &Origin) -> AppResult<()>;
+
+    /// Replace every entry with the content of `version`.
+    fn replace_all(&mut self, version: &LocalVersion) -> AppResult<()>;
+
+    /// Persist the pending changes.
+    fn flush(&mut self) -> AppResult<()>;
+
+    /// File where the database is stored.
+    fn location(&self) -> &Path;
+}
+
+/// Backend used to store the local version database.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum DbBackend {
+    /// Single JSON file rewritten on every save, fine for small trees.
+    #[default]
+    Json,
+    /// Embedded SQLite database with per entry updates.
+    Sqlite,
+}
+
+/// Names of the databases stored inside the out dir by older versions.
+const LEGACY_LOCATIONS: [&str; 2] = [".sync", ".sync.db"];
+
+/// File left in the legacy location of the out dir, telling where its db is stored now.
+/// Its schema version makes older versions fail asking for an update, instead of syncing
+/// again from an empty db.
+const MOVED_MARKER: &str = ".sync";
+
+/// Schema version of the moved marker, above any real one.
+const MOVED_SCHEMA_VERSION: u32 = u32::MAX;
+
+/// Marker files are tiny, bigger files in its place are dbs.
+const MAX_MARKER_SIZE: u64 = 64 * 1024;
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+impl DbBackend {
+    /// Backend of the database in `location`, if it exists.
+    pub fn detect(location: &Path) -> AppResult<Option<DbBackend>> {
+        let mut file = match File::open(location) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut header = [0; 16];
+        let read = file.read(&mut header)?;
+        if header[..read] == *SQLITE_HEADER {
+            return Ok(Some(DbBackend::Sqlite));
+        }
+
+        Ok(Some(DbBackend::Json))
+    }
+
+    pub fn open(&self, location: PathBuf) -> AppResult<Box<dyn VersionStore>> {
+        let store: Box<dyn VersionStore> = match self {
+            DbBackend::Json => Box::new(JsonStore::open(location)?),
+            DbBackend::Sqlite => Box::new(SqliteStore::open(location)?),
+        };
+
+        Ok(store)
+    }
+}
+
+/// Open the database in `location` with `backend`, converting it first if
+/// it was stored with another backend.
+pub fn open_store(location: &Path, backend: DbBackend) -> AppResult<Box<dyn VersionStore>> {
+    match DbBackend::detect(location)? {
+        Some(current) if current != backend => {
+            info!(from = ?current, to = ?backend, "converting local db...");
+            convert(location, current, backend)
+        }
+        _ => backend.open(location.to_path_buf()),
+    }
+}
+
+/// Open the database in `location` with the backend it was stored with.
+pub fn open_existing(location: &Path) -> AppResult<Option<Box<dyn VersionStore>>> {
+    match DbBackend::detect(location)? {
+        Some(backend) => Ok(Some(backend.open(location.to_path_buf())?)),
+        None => Ok(None),
+    }
+}
+
+/// Rewrite the database in `location` with another backend.
+pub fn convert(
+    location: &Path,
+    from: DbBackend,
+    to: DbBackend,
+) -> AppResult<Box<dyn VersionStore>> {
+    if from == to {
+        return Err(format!("local db already uses the {:?} backend", to).into());
+    }
+
+    let source = from.open(location.to_path_buf())?;
+    let version = source.load()?;
+
+    let mut converted_location = location.as_os_str().to_os_string();
+    converted_location.push(".converting");
+    let converted_location = PathBuf::from(converted_location);
+    let mut target = to.open(converted_location.clone())?;
+    target.replace_all(&version)?;
+    target.flush()?;
+    drop(target);
+
+    remove(source)?;
+    std::fs::rename(&converted_location, location)?;
+
+    to.open(location.to_path_buf())
+}
+
+/// Close `store` and delete its files.
+pub fn remove(store: Box<dyn VersionStore>) -> AppResult<()> {
+    let location = store.location().to_path_buf();
+    drop(store);
+
+    std::fs::remove_file(&location)?;
+    for suffix in ["-wal", "-shm"] {
+        let mut sqlite_file = location.clone().into_os_string();
+        sqlite_file.push(suffix);
+        let _ = std::fs::remove_file(sqlite_file);
+    }
+
+    Ok(())
+}
+
+/// Database stored inside `out_dir` by older versions, if there is one.
+pub fn find_legacy(out_dir: &Path) -> Option<PathBuf> {
+    LEGACY_LOCATIONS
+        .iter()
+        .map(|name| out_dir.join(name))
+        .find(|legacy| legacy.is_file() && read_moved_marker(legacy).is_none())
+}
+
+/// Database of `out_dir` recorded in its moved marker, if there is one.
+pub fn find_moved(out_dir: &Path) -> Option<PathBuf> {
+    read_moved_marker(&out_dir.join(MOVED_MARKER))
+}
+
+/// Leave the moved marker pointing to `location` in `out_dir`, unless the db is stored there.
+pub fn mark_moved(location: &Path, out_dir: &Path) -> AppResult<()> {
+    let marker = out_dir.join(MOVED_MARKER);
+    if marker == location || !out_dir.is_dir() {
+        return Ok(());
+    }
+
+    match read_moved_marker(&marker) {
+        Some(moved_to) if moved_to == location => return Ok(()),
+        // a legacy db not relocated yet
+        None if marker.exists() => return Ok(()),
+        _ => {}
+    }
+
+    let content = json!({
+        "schema_version": MOVED_SCHEMA_VERSION,
+        "moved_to": std::path::absolute(location)?,
+    });
+    std::fs::write(marker, serde_json::to_string_pretty(&content)?)?;
+
+    Ok(())
+}
+
+/// Remove the moved marker of `out_dir`, if there is one.
+pub fn remove_moved_marker(out_dir: &Path) -> AppResult<()> {
+    let marker = out_dir.join(MOVED_MARKER);
+    if read_moved_marker(&marker).is_some() {
+        std::fs::remove_file(marker)?;
+    }
+
+    Ok(())
+}
+
+/// Location recorded in `path`, if it is a moved marker.
+fn read_moved_marker(path: &Path) -> Option<PathBuf> {
+    if std::fs::metadata(path).ok()?.len() > MAX_MARKER_SIZE {
+        return None;
+    }
+
+    let content: Value = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    if content.get("schema_version")?.as_u64()? != MOVED_SCHEMA_VERSION as u64 {
+        return None;
+    }
+
+    content.get("moved_to")?.as_str().map(PathBuf::from)
+}
+
+/// Location to read the database of `out_dir` from without moving it: `location`, or the
+/// legacy database in `out_dir` while it has not been relocated.
+pub fn existing_location(location: &Path, out_dir: &Path) -> PathBuf {
+    if location.exists() {
+        return location.to_path_buf();
+    }
+
+    find_legacy(out_dir).unwrap_or_else(|| location.to_path_buf())
+}
+
+/// Move the database stored inside `out_dir` by older versions to `location`, if there is one.
+pub fn relocate_legacy(location: &Path, out_dir: &Path) -> AppResult<()> {
+    if location.exists() {
+        return Ok(());
+    }
+
+    let Some(legacy) = find_legacy(out_dir).filter(|legacy| legacy != location) else {
+        return Ok(());
+    };
+
+    info!(
+        from = %legacy.display(),
+        to = %location.display(),
+        "moving local db"
+    );
+    if let Some(parent) = location.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    if DbBackend::detect(&legacy)? == Some(DbBackend::Sqlite) {
+        // checkpoint the SQLite journal, so the main file has every entry
+        drop(SqliteStore::open(legacy.clone())?);
+    }
+
+    if std::fs::rename(&legacy, location).is_err() {
+        std::fs::copy(&legacy, location)?;
+        std::fs::remove_file(&legacy)?;
+    }
+
+    Ok(())
+}
+
+/// Pretty printed JSON file, kept in memory and rewritten in full on `flush`.
+pub struct JsonStore {
+    location: PathBuf,
+    version: LocalVersion,
+}
+
+impl JsonStore {
+    pub fn open(location: PathBuf) -> AppResult<Self> {
+        let version = LocalVersion::load_from_file(&location)?;
+
+        Ok(Self { location, version })
+    }
+}
+
+impl VersionStore for JsonStore {
+    fn load(&self) -> AppResult<LocalVersion> {
+        Ok(self.version.clone())
+    }
+
+    fn get(&self, href: &Href) -> AppResult<Option<LocalFile>> {
+        Ok(self.version.get(href).cloned())
+    }
+
+    fn find_by_path(&self, path: &Path) -> AppResult<Option<(Href, LocalFile)>> {
+        let found = self
+            .version
+            .iter()
+            .find(|(_, file)| file.path == path)
+            .map(|(href, file)| (href.clone(), file.clone()));
+
+        Ok(found)
+    }
+
+    fn put(&mut self, href: &Href, file: &LocalFile) -> AppResult<()> {
+        self.version.add(href.clone(), file.clone());
+
+        Ok(())
+    }
+
+    fn delete(&mut self, href: &Href) -> AppResult<()> {
+        self.version.remove(href);
+
+        Ok(())
+    }
+
+    fn set_origin(&mut self, origin: &Origin) -> AppResult<()> {
+        self.version.set_origin(Some(origin.clone()));
+
+        Ok(())
+    }
+
+    fn replace_all(&mut self, version: &LocalVersion) -> AppResult<()> {
+        self.version = version.clone();
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AppResult<()> {
+        self.version.save_in_file(&self.location)
+    }
+
+    fn location(&self) -> &Path {
+        &self.location
+    }
+}
+
+/// SQLite database, every entry is stored as JSON indexed by href and local path.
+pub struct SqliteStore {
+    location: PathBuf,
+    connection: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(location: PathBuf) -> AppResult<Self> {
+        let mut connection = Connection::open(&location)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;",
+        )?;
+        migrations::migrate_sqlite(&location, &mut connection)?;
+
+        Ok(Self {
+            location,
+            connection,
+        })
+    }
+
+    fn insert(connection: &Connection, href: &Href, file: &LocalFile) -> AppResult<()> {
+        connection.execute(
+            "INSERT OR REPLACE INTO files (href, path, data) VALUES (?1, ?2, ?3)",
+            params![
+                href,
+                file.path.to_string_lossy(),
+                serde_json::to_string(file)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn write_origin(connection: &Connection, origin: Option<&Origin>) -> AppResult<()> {
+        match origin {
+            Some(origin) => connection.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('origin', ?1)",
+                [serde_json::to_string(origin)?],
+            )?,
+            None => connection.execute("DELETE FROM meta WHERE key = 'origin'", [])?,
+        };
+
+        Ok(())
+    }
+}
+
+impl VersionStore for SqliteStore {
+    fn load(&self) -> AppResult<LocalVersion> {
+        let mut statement = self.connection.prepare("SELECT href, data FROM files")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut version = LocalVersion::default();
+        for row in rows {
+            let (href, data) = row?;
+            version.add(href, serde_json::from_str(&data)?);
+        }
+
+        let origin: Option<String> = self
+            .connection
+            .query_row("SELECT value FROM meta WHERE key = 'origin'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(origin) = origin {
+            version.set_origin(Some(serde_json::from_str(&origin)?));
+        }
+
+        Ok(version)
+    }
+
+    fn get(&self, href: &Href) -> AppResult<Option<LocalFile>> {
+        let data: Option<String> = self
+            .connection
+            .query_row("SELECT data FROM files WHERE href = ?1", [href], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        match data {
+            Some(data) => Ok(Some(serde_json::from_str(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn find_by_path(&self, path: &Path) -> AppResult<Option<(Href, LocalFile)>> {
+        let row: Option<(String, String)> = self
+            .connection
+            .query_row(
+                "SELECT href, data FROM files WHERE path = ?1",
+                [path.to_string_lossy()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        match row {
+            Some((href, data)) => Ok(Some((href, serde_json::from_str(&data)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&mut self, href: &Href, file: &LocalFile) -> AppResult<()> {
+        Self::insert(&self.connection, href, file)
+    }
+
+    fn delete(&mut self, href: &Href) -> AppResult<()> {
+        self.connection
+            .execute("DELETE FROM files WHERE href = ?1", [href])?;
+
+        Ok(())
+    }
+
+    fn set_origin(&mut self, origin: &Origin) -> AppResult<()> {
+        Self::write_origin(&self.connection, Some(origin))
+    }
+
+    fn replace_all(&mut self, version: &LocalVersion) -> AppResult<()> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute("DELETE FROM files", [])?;
+        for (href, file) in version.iter() {
+            Self::insert(&transaction, href, file)?;
+        }
+        Self::write_origin(&transaction, version.origin())?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> AppResult<()> {
+        Ok(())
+    }
+
+    fn location(&self) -> &Path {
+        &self.location
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_file(path: &str) -> LocalFile {
+        LocalFile {
+            path: PathBuf::from(path),
+            is_dir: false,
+            last_modified: None,
+            local_modified: None,
+            size: Some(1),
+            checksum: None,
+            file_id: Some("7".to_string()),
+        }
+    }
+
+    fn origin() -> Origin {
+        Origin {
+            host: "https://cloud.example.com/".to_string(),
+            remote_dir: "/remote.php/dav/files/user/docs/".to_string(),
+        }
+    }
+
+    #[test]
+    fn convert_json_to_sqlite_and_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = dir.path().join("db");
+        let href = "/dav/a.txt".to_string();
+
+        let mut json = open_store(&location, DbBackend::Json).unwrap();
+        json.put(&href, &local_file("/out/a.txt")).unwrap();
+        json.set_origin(&origin()).unwrap();
+        json.flush().unwrap();
+        drop(json);
+
+        let sqlite = open_store(&location, DbBackend::Sqlite).unwrap();
+        assert_eq!(
+            DbBackend::detect(&location).unwrap(),
+            Some(DbBackend::Sqlite)
+        );
+        let version = sqlite.load().unwrap();
+        assert_eq!(version.origin(), Some(&origin()));
+        assert_eq!(
+            version.get(&href).unwrap().path,
+            PathBuf::from("/out/a.txt")
+        );
+        let (found, _) = sqlite
+            .find_by_path(Path::new("/out/a.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(found, href);
+        drop(sqlite);
+
+        let json = open_store(&location, DbBackend::Json).unwrap();
+        assert_eq!(DbBackend::detect(&location).unwrap(), Some(DbBackend::Json));
+        assert_eq!(json.load().unwrap().len(), 1);
+        assert!(!dir.path().join("db.converting").exists());
+    }
+
+    #[test]
+    fn relocate_legacy_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        std::fs::write(out_dir.join(".sync"), r#"{"files": {}}"#).unwrap();
+        let location = dir.path().join("state/db");
+
+        relocate_legacy(&location, &out_dir).unwrap();
+        assert!(location.is_file());
+        assert!(!out_dir.join(".sync").exists());
+    }
+
+    #[test]
+    fn moved_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let location = dir.path().join("state/db");
+
+        mark_moved(&location, &out_dir).unwrap();
+        assert_eq!(find_moved(&out_dir), Some(location.clone()));
+        assert_eq!(find_legacy(&out_dir), None);
+        assert_eq!(existing_location(&location, &out_dir), location);
+
+        // versions reading the legacy location refuse it
+        let err = LocalVersion::load_from_file(&out_dir.join(".sync")).unwrap_err();
+        assert!(err.to_string().contains("update nubesync"), "{}", err);
+
+        remove_moved_marker(&out_dir).unwrap();
+        assert!(!out_dir.join(".sync").exists());
+    }
+
+    #[test]
+    fn legacy_db_is_not_marked() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = dir.path().join(".sync");
+        std::fs::write(&legacy, r#"{"files": {}}"#).unwrap();
+
+        mark_moved(&dir.path().join("db"), dir.path()).unwrap();
+        assert_eq!(find_legacy(dir.path()), Some(legacy));
+        assert_eq!(find_moved(dir.path()), None);
+    }
+}
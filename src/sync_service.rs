@@ -1,10 +1,19 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use ::tokio::fs::DirBuilder;
 use reqwest_dav::{
     list_cmd::{ListEntity, ListFile, ListFolder},
-    Auth, Client, ClientBuilder, Depth,
+    Auth, Client, ClientBuilder,
 };
+use tracing::{debug, error, info, instrument, warn};
 use url::Url;
 
 #[cfg(feature = "version_migration")]
@@ -13,22 +22,67 @@ use getset::Getters;
 use named_ctor::NamedCtor;
 
 use crate::{
+    archive::Archive,
+    bandwidth::BandwidthLimiter,
+    checksum::{Checksum, ChecksumAlgorithm, ContentDigest, ContentHasher},
     config::Config,
     conn_retry::DEFAULT_CONN_RETRY,
+    control::PairControl,
+    dav::{self, OcProps, CHECKSUM_HEADER},
+    events::{EventKind, EventSink},
+    hooks::Hook,
+    lock::RunLock,
+    metrics::{PairMetrics, METRICS},
+    notifications::Notifier,
+    notify_push::NotifyPush,
+    progress::{ProgressMode, SyncProgress},
+    report::{ReportEntry, SyncReport},
     result::AppResult,
-    versions::{Href, LocalFile, LocalVersion, VersionService},
+    store::{self, VersionStore},
+    trash::{self, DeletionMode, Trash},
+    versions::{Href, LocalFile, LocalVersion, Move, Origin, Tampering, VersionService},
 };
 
+/// Times a download is attempted when its content does not match the server checksum.
+const CHECKSUM_TRIES: usize = 3;
+
 #[cfg_attr(feature = "version_migration", derive(Getters, NamedCtor))]
 #[cfg_attr(feature = "version_migration", getset(get = "pub"))]
 pub struct SyncService {
     config: Config,
     client: Client,
     local_version: LocalVersion,
+    store: Box<dyn VersionStore>,
+    bandwidth: BandwidthLimiter,
+    /// Held until the service is dropped, so other runs don't use the same db.
+    _lock: RunLock,
+    shutdown: Option<Shutdown>,
+    events: Option<EventSink>,
+    control: Option<Arc<PairControl>>,
+    progress: Option<ProgressMode>,
+    /// Outcome of the current or last sync.
+    report: SyncReport,
+    report_location: Option<PathBuf>,
+    metrics: Option<Arc<PairMetrics>>,
+    notifier: Notifier,
 }
 
 impl SyncService {
-    pub fn init(config: Config) -> AppResult<SyncService> {
+    /// Open the local db in `db_location`, waiting for other runs using it if `wait` is set.
+    pub async fn init(config: Config, db_location: &Path, wait: bool) -> AppResult<SyncService> {
+        let bandwidth =
+            BandwidthLimiter::new(config.bandwidth_limit, config.bandwidth_schedule.clone());
+
+        Self::init_with_limiter(config, db_location, wait, bandwidth).await
+    }
+
+    /// Same as `init`, with the downloads sharing the rate of `bandwidth` with other services.
+    pub async fn init_with_limiter(
+        config: Config,
+        db_location: &Path,
+        wait: bool,
+        bandwidth: BandwidthLimiter,
+    ) -> AppResult<SyncService> {
         let reqwest_client = reqwest::ClientBuilder::new().use_rustls_tls().build()?;
         let client = ClientBuilder::new()
             .set_agent(reqwest_client)
@@ -39,79 +93,678 @@ impl SyncService {
             ))
             .build()?;
 
+        if let Some(parent) = db_location.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let lock = RunLock::acquire_async(db_location, wait).await?;
+        store::relocate_legacy(db_location, &config.out_dir)?;
+        let store = store::open_store(db_location, config.db_backend)?;
+        store::mark_moved(db_location, &config.out_dir)?;
         let service = SyncService {
             client,
-            local_version: LocalVersion::load_from_file(config.out_dir.clone())?,
+            local_version: store.load()?,
+            store,
+            bandwidth,
             config,
+            _lock: lock,
+            shutdown: None,
+            events: None,
+            control: None,
+            progress: None,
+            report: SyncReport::default(),
+            report_location: None,
+            metrics: None,
+            notifier: Notifier::default(),
         };
 
         Ok(service)
     }
 
-    pub async fn sync(&mut self, remote_dir: &str) -> AppResult<()> {
-        println!("sync location: {}...", remote_dir);
-        let server_files = DEFAULT_CONN_RETRY
-            .execute_with_retries(|| self.client.list(remote_dir, Depth::Infinity))
+    /// Stop syncs and watches before the next transfer once `shutdown` is set.
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = Some(shutdown);
+    }
+
+    /// Publish the progress of the syncs in `events`.
+    pub fn set_events(&mut self, events: EventSink) {
+        self.events = Some(events);
+    }
+
+    /// Pause and wake up the watch loop with `control`.
+    pub fn set_control(&mut self, control: Arc<PairControl>) {
+        self.control = Some(control);
+        self.share_version(true);
+    }
+
+    /// Share the synced entries with the control socket, or hide them while a sync changes them.
+    fn share_version(&self, synced: bool) {
+        if let Some(control) = &self.control {
+            control.set_version(synced.then(|| self.local_version.clone()));
+        }
+    }
+
+    /// Show the progress of the downloads with `mode`.
+    pub fn set_progress(&mut self, mode: ProgressMode) {
+        self.progress = Some(mode);
+    }
+
+    /// Write the report of each sync in `location`.
+    pub fn set_report(&mut self, location: PathBuf) {
+        self.report_location = Some(location);
+    }
+
+    /// Add the outcome of each sync to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<PairMetrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Send the notifications through `notifier`, sharing its rate limit with other services.
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = notifier;
+    }
+
+    fn emit(&self, kind: EventKind) {
+        if let Some(events) = &self.events {
+            events.emit(kind);
+        }
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .is_some_and(|shutdown| *shutdown.borrow())
+    }
+
+    fn paused(&self) -> bool {
+        self.control
+            .as_ref()
+            .is_some_and(|control| control.is_paused())
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.shutdown_requested() || self.paused()
+    }
+
+    #[instrument(name = "sync", skip_all, fields(remote = remote_dir))]
+    pub async fn sync(&mut self, remote_dir: &str, options: SyncOptions) -> AppResult<()> {
+        self.emit(EventKind::SyncStarted);
+        self.share_version(false);
+        self.report = SyncReport::new(remote_dir);
+        let mut result = self.run_sync(remote_dir, options).await;
+        self.share_version(true);
+        let error = result.as_ref().err().map(|err| err.to_string());
+        if let Err(err) = self.run_post_sync_hook(error.as_deref()).await {
+            error!(%err, "error running post_sync hook");
+            result = result.and(Err(err));
+        }
+
+        // after the hook, so its failure is in the report, the metrics and the notification
+        let error = result.as_ref().err().map(|err| err.to_string());
+        self.report.finish(error.clone());
+        if let Some(metrics) = &self.metrics {
+            metrics.record(&self.report);
+        }
+        self.emit(EventKind::SyncFinished { error });
+
+        self.notifier
+            .notify(
+                &self.config.notifications,
+                &self.client.agent,
+                &self.config.out_dir,
+                &self.report,
+            )
+            .await;
+
+        if let Some(location) = &self.report_location {
+            if let Err(err) = self.report.save_in_file(location) {
+                error!(%err, path = %location.display(), "error writing sync report");
+                result = result.and(Err(err));
+            }
+        }
+
+        result
+    }
+
+    async fn run_sync(&mut self, remote_dir: &str, options: SyncOptions) -> AppResult<()> {
+        info!("syncing...");
+        self.config
+            .hooks
+            .run(
+                Hook::PreSync,
+                &self.config.out_dir,
+                &[("NUBESYNC_REMOTE", OsStr::new(remote_dir))],
+                self.shutdown.clone(),
+            )
+            .await?;
+        self.check_origin(remote_dir, options.rebind)?;
+
+        let listing = DEFAULT_CONN_RETRY
+            .execute_with_retries(|| dav::list(&self.client, remote_dir))
             .await?;
-        let version_service = VersionService::init(self.local_version.clone(), server_files);
+        if let Some(mode) = options.verify {
+            self.verify_local_files(mode, &listing.entities)?;
+        }
+        let version_service =
+            VersionService::init(self.local_version.clone(), listing.entities, &listing.props);
 
-        self.delete_locals(version_service.version().files_to_remove())?;
+        let mut files_to_remove = version_service.version().files_to_remove();
+        if !options.force_delete {
+            self.check_delete_threshold(files_to_remove.len())?;
+        }
 
-        let to_sycn_files = version_service.entities_to_download();
+        let skipped_moves = self.apply_moves(remote_dir, version_service.version().moves())?;
+        files_to_remove.extend(skipped_moves.iter().map(|m| m.from.clone()));
+        self.delete_locals(files_to_remove).await?;
 
-        self.apply_sync(remote_dir, to_sycn_files).await?;
+        let mut to_sycn_files = version_service.entities_to_download();
+        to_sycn_files.extend(
+            version_service
+                .entities()
+                .iter()
+                .filter(|entity| skipped_moves.iter().any(|m| m.to == *entity_href(entity)))
+                .cloned(),
+        );
 
-        self.local_version.save_in_file(&self.config.out_dir)
+        self.apply_sync(remote_dir, to_sycn_files, &listing.props)
+            .await?;
+
+        store::mark_moved(self.store.location(), &self.config.out_dir)?;
+        self.store.flush()?;
+
+        match self.report.failed.len() {
+            0 => Ok(()),
+            failed => Err(format!("{} entries failed to sync", failed).into()),
+        }
     }
 
-    /// Remove files deleted on the server.
-    fn delete_locals(&mut self, to_detele: Vec<String>) -> AppResult<()> {
+    /// Sync periodically until the shutdown is set, keeping the client and the local version in memory.
+    pub async fn watch(
+        &mut self,
+        remote_dir: &str,
+        options: SyncOptions,
+        watch: WatchOptions,
+    ) -> AppResult<()> {
+        let mut delay = watch.interval;
+        let mut use_push = watch.push;
+        let mut push = None;
+        let mut shutdown = self.shutdown.clone();
+        let control = self.control.clone();
+        'watch: loop {
+            if let Some(control) = control.as_ref().filter(|control| control.is_paused()) {
+                info!("sync paused");
+                tokio::select! {
+                    _ = control.woken() => continue,
+                    _ = wait_shutdown(shutdown.as_mut()) => break,
+                }
+            }
+
+            let result = self.sync(remote_dir, options).await;
+            if self.shutdown_requested() {
+                break;
+            }
+
+            match result {
+                Ok(()) => delay = watch.interval,
+                Err(err) => {
+                    error!(%err, "sync failed");
+                    delay = (delay * 2).min(watch.max_backoff.max(watch.interval));
+                }
+            }
+
+            if use_push && push.is_none() {
+                match self.connect_push().await {
+                    Ok(Some(connected)) => push = Some(connected),
+                    Ok(None) => {
+                        info!("notify_push is not available in the server, polling only");
+                        use_push = false;
+                    }
+                    Err(err) => warn!(%err, "error connecting to notify_push"),
+                }
+            }
+
+            debug!(delay = delay.as_secs(), "next sync scheduled");
+            let next_sync = tokio::time::sleep(delay);
+            tokio::pin!(next_sync);
+            loop {
+                let file_changed = async {
+                    match push.as_mut() {
+                        Some(push) => push.file_changed().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let woken = async {
+                    match control.as_ref() {
+                        Some(control) => control.woken().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let wake = tokio::select! {
+                    _ = &mut next_sync => Some(Ok(())),
+                    result = file_changed => Some(result),
+                    _ = woken => Some(Ok(())),
+                    _ = wait_shutdown(shutdown.as_mut()) => None,
+                };
+
+                // the scheduled sync connects again
+                match wake {
+                    Some(Err(err)) => {
+                        warn!(%err, "polling until notify_push is reconnected");
+                        push = None;
+                    }
+                    Some(Ok(())) => break,
+                    None => break 'watch,
+                }
+            }
+        }
+
+        info!("stopping watch...");
+        self.store.flush()
+    }
+
+    /// Log in the `notify_push` websocket of the server, `None` if there is not one.
+    async fn connect_push(&self) -> AppResult<Option<NotifyPush>> {
+        let Some(endpoint) = NotifyPush::discover(&self.client, &self.config).await? else {
+            return Ok(None);
+        };
+
+        let push = NotifyPush::connect(&endpoint, &self.config).await?;
+        info!(endpoint, "listening to changes");
+
+        Ok(Some(push))
+    }
+
+    /// Download again the local entries missing or changed since the last sync.
+    pub async fn repair(&mut self, remote_dir: &str, mode: VerifyMode) -> AppResult<()> {
+        self.check_origin(remote_dir, false)?;
+        let listing = DEFAULT_CONN_RETRY
+            .execute_with_retries(|| dav::list(&self.client, remote_dir))
+            .await?;
+        let tampered = self.verify_local_files(mode, &listing.entities)?;
+        if tampered.is_empty() {
+            info!("all local files are in sync");
+            return Ok(());
+        }
+
+        let to_repair = listing
+            .entities
+            .into_iter()
+            .filter(|entity| tampered.contains(entity_href(entity)))
+            .collect();
+
+        self.apply_sync(remote_dir, to_repair, &listing.props)
+            .await?;
+
+        self.store.flush()
+    }
+
+    /// Check every tracked entry on disk and forget the altered ones, so they are downloaded again.
+    /// Entries missing in the server `entities` are kept, so the sync deletes them.
+    fn verify_local_files(
+        &mut self,
+        mode: VerifyMode,
+        entities: &[ListEntity],
+    ) -> AppResult<Vec<Href>> {
+        let remote: HashSet<&Href> = entities.iter().map(entity_href).collect();
+        let check_content = mode == VerifyMode::Content;
+        let mut tampered = Vec::new();
+        for (href, file) in self.local_version.iter() {
+            if !remote.contains(href) {
+                continue;
+            }
+            if let Some(reason) = file.verify(check_content)? {
+                warn!(path = %file.path.display(), %reason, "local change");
+                tampered.push(href.clone());
+            }
+        }
+
+        for href in &tampered {
+            self.untrack(href)?;
+        }
+
+        Ok(tampered)
+    }
+
+    /// Record a synced entry in memory and in the store.
+    fn track(&mut self, href: Href, file: LocalFile) -> AppResult<()> {
+        self.store.put(&href, &file)?;
+        self.local_version.add(href, file);
+
+        Ok(())
+    }
+
+    /// Forget a synced entry in memory and in the store.
+    fn untrack(&mut self, href: &Href) -> AppResult<Option<LocalFile>> {
+        self.store.delete(href)?;
+
+        Ok(self.local_version.remove(href))
+    }
+
+    /// Rename local entries renamed or moved on the server, instead of downloading them again.
+    /// Files in the new location are archived if versioning is enabled. Otherwise, and when the
+    /// entry is missing locally, the move is skipped and returned, to delete and download it instead.
+    fn apply_moves(&mut self, remote_dir: &str, moves: &[Move]) -> AppResult<Vec<Move>> {
+        // parents first, so the entries moved along with them are already in their new place
+        let mut moves = moves.to_vec();
+        moves.sort_by(|a, b| a.from.cmp(&b.from));
+
+        let mut skipped = Vec::new();
+        for m in &moves {
+            let Some(old_path) = self
+                .local_version
+                .get(&m.from)
+                .map(|file| file.path.clone())
+            else {
+                continue;
+            };
+
+            let new_path = self.define_paths(remote_dir, &m.to)?.local;
+            if old_path == new_path {
+                // already moved along with its parent folder
+                let file = self.untrack(&m.from)?.unwrap();
+                self.track(m.to.clone(), file)?;
+                continue;
+            }
+
+            let old_exists = old_path.exists();
+            match (old_exists, new_path.exists()) {
+                // the file in the new location is not this entry
+                (false, _) => {
+                    skipped.push(m.clone());
+                    continue;
+                }
+                (true, true) => match &self.config.versioning {
+                    Some(versioning) if new_path.is_file() => {
+                        info!(path = %new_path.display(), "archiving local file in the way of a move");
+                        Archive::new(&self.config.out_dir, versioning.clone())
+                            .archive(&new_path)?;
+                    }
+                    _ => {
+                        warn!(
+                            from = %old_path.display(),
+                            to = %new_path.display(),
+                            "move target already exists locally, downloading it instead"
+                        );
+                        skipped.push(m.clone());
+                        continue;
+                    }
+                },
+                (true, false) => {}
+            }
+
+            let mut file = self.untrack(&m.from)?.unwrap();
+            info!(
+                from = %file.path.display(),
+                to = %new_path.display(),
+                "moving local"
+            );
+            if let Some(parent) = new_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(&file.path, &new_path)?;
+            if file.is_dir {
+                self.track_moved_content(&file.path, &new_path)?;
+            }
+
+            file.path = new_path;
+            self.track(m.to.clone(), file)?;
+        }
+
+        Ok(skipped)
+    }
+
+    /// Point the entries inside a folder moved from `from` to their path in `to`.
+    fn track_moved_content(&mut self, from: &Path, to: &Path) -> AppResult<()> {
+        let moved: Vec<(Href, LocalFile)> = self
+            .local_version
+            .iter()
+            .filter_map(|(href, file)| {
+                let relative = file.path.strip_prefix(from).ok()?;
+                let mut file = file.clone();
+                file.path = to.join(relative);
+                Some((href.clone(), file))
+            })
+            .collect();
+
+        for (href, file) in moved {
+            self.track(href, file)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the local db was synced with another host or remote location, unless `rebind` is set.
+    /// Dbs without origin are bound to the current one.
+    fn check_origin(&mut self, remote_dir: &str, rebind: bool) -> AppResult<()> {
+        let origin = Origin {
+            host: self.config.host.to_string(),
+            remote_dir: remote_dir.to_string(),
+        };
+
+        match self.local_version.origin() {
+            Some(current) if *current == origin => return Ok(()),
+            Some(current) if !rebind => {
+                return Err(format!(
+                    "local db belongs to {}, not to {}. Use --rebind to sync it with the new location, \
+                    the entries missing there will be deleted",
+                    current, origin
+                )
+                .into())
+            }
+            Some(current) => warn!(from = %current, to = %origin, "rebinding local db"),
+            None => {}
+        }
+
+        self.store.set_origin(&origin)?;
+        self.local_version.set_origin(Some(origin));
+
+        Ok(())
+    }
+
+    /// Fail if the planned deletions exceed the limits in config.
+    fn check_delete_threshold(&self, to_delete: usize) -> AppResult<()> {
+        if to_delete == 0 {
+            return Ok(());
+        }
+
+        if let Some(max_count) = self.config.max_delete_count {
+            if to_delete > max_count {
+                return Err(format!(
+                    "sync aborted: {} entries to delete exceeds max_delete_count ({}), use --force-delete to proceed",
+                    to_delete, max_count
+                )
+                .into());
+            }
+        }
+
+        if let Some(max_percent) = self.config.max_delete_percent {
+            let percent = to_delete as f64 * 100.0 / self.local_version.len() as f64;
+            if percent > max_percent {
+                return Err(format!(
+                    "sync aborted: deleting {:.1}% of the tracked entries exceeds max_delete_percent ({}%), use --force-delete to proceed",
+                    percent, max_percent
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the post_sync hook with the outcome of the sync, before the report is finished.
+    async fn run_post_sync_hook(&self, error: Option<&str>) -> AppResult<()> {
+        let report = &self.report;
+        let result = if error.is_some() {
+            "failure"
+        } else {
+            "success"
+        };
+        let downloaded = report.downloaded.len().to_string();
+        let deleted = report.deleted.len().to_string();
+        let failed = report.failed.len().to_string();
+
+        self.config
+            .hooks
+            .run(
+                Hook::PostSync,
+                &self.config.out_dir,
+                &[
+                    ("NUBESYNC_REMOTE", OsStr::new(&report.remote)),
+                    ("NUBESYNC_RESULT", OsStr::new(result)),
+                    ("NUBESYNC_ERROR", OsStr::new(error.unwrap_or_default())),
+                    ("NUBESYNC_DOWNLOADED", OsStr::new(&downloaded)),
+                    ("NUBESYNC_DELETED", OsStr::new(&deleted)),
+                    ("NUBESYNC_FAILED", OsStr::new(&failed)),
+                ],
+                self.shutdown.clone(),
+            )
+            .await
+    }
+
+    /// Run a per file hook, its failures are logged without stopping the sync.
+    /// It runs before the next transfer, so a slow hook slows down the sync.
+    async fn run_file_hook(&self, hook: Hook, href: &str, path: &Path) {
+        let env = [
+            ("NUBESYNC_HREF", OsStr::new(href)),
+            ("NUBESYNC_PATH", path.as_os_str()),
+        ];
+        if let Err(err) = self
+            .config
+            .hooks
+            .run(hook, &self.config.out_dir, &env, self.shutdown.clone())
+            .await
+        {
+            warn!(%err, path = %path.display(), "error running file hook");
+        }
+    }
+
+    /// Remove files deleted on the server. The entries that can't be removed stay tracked
+    /// and are recorded as failed in the report.
+    async fn delete_locals(&mut self, mut to_detele: Vec<String>) -> AppResult<()> {
+        // parents first, so their content is moved along with them
+        to_detele.sort();
+        let trash = Trash::new(&self.config.out_dir);
+        let batch = Trash::new_batch();
+
         let mut folders_to_delete = Vec::new();
         for href in to_detele {
-            let file = self.local_version.remove(&href).unwrap();
+            let Some(file) = self.local_version.get(&href).cloned() else {
+                continue;
+            };
             let path = &file.path;
 
-            if path.is_dir() {
+            let removed = if path.is_dir() {
                 folders_to_delete.push(path.clone());
-                println!("deleting local folder: {}", path.display());
-                std::fs::remove_dir_all(path)?;
-
+                info!(path = %path.display(), "deleting local folder");
+                self.remove_local(&trash, &batch, path)
+            } else if path.is_file() {
+                info!(path = %path.display(), "deleting local file");
+                self.remove_local(&trash, &batch, path)
+            } else {
+                self.untrack(&href)?;
+                continue;
+            };
+            if let Err(err) = removed {
+                error!(%err, path = %path.display(), "error deleting local entry");
+                self.report.fail(&href, &err.to_string());
                 continue;
             }
+            self.untrack(&href)?;
 
-            if path.is_file() {
-                println!("deleting local file: {}", path.display());
-                std::fs::remove_file(path)?;
-            }
+            self.report
+                .deleted
+                .push(ReportEntry::new(&href).with_path(&file.path));
+            self.run_file_hook(Hook::FileDeleted, &href, &file.path)
+                .await;
+            self.emit(EventKind::FileDeleted {
+                href,
+                path: file.path,
+            });
+        }
+
+        if let (DeletionMode::Trash, Some(days)) =
+            (self.config.deletion_mode, self.config.trash_retention_days)
+        {
+            trash.purge(Some(chrono::Duration::days(days as i64)))?;
         }
 
         Ok(())
     }
 
-    async fn apply_sync(&mut self, remote_dir: &str, files: Vec<ListEntity>) -> AppResult<()> {
-        for f in files {
-            match f {
-                ListEntity::File(file) => {
-                    if self.is_in_black_list(&file.href)? {
-                        continue;
-                    }
+    /// Erase `path` or move it to the trash, according to the deletion mode in config.
+    fn remove_local(&self, trash: &Trash, batch: &str, path: &Path) -> AppResult<()> {
+        match self.config.deletion_mode {
+            DeletionMode::Delete if path.is_dir() => std::fs::remove_dir_all(path)?,
+            DeletionMode::Delete => std::fs::remove_file(path)?,
+            DeletionMode::Trash => trash.move_to_trash(batch, path)?,
+            DeletionMode::SystemTrash => trash::move_to_system_trash(path)?,
+        }
 
-                    self.download_file(&file, remote_dir).await?;
+        Ok(())
+    }
+
+    async fn apply_sync(
+        &mut self,
+        remote_dir: &str,
+        files: Vec<ListEntity>,
+        props: &HashMap<Href, OcProps>,
+    ) -> AppResult<()> {
+        let mut entities = Vec::new();
+        for entity in files {
+            if self.is_in_black_list(entity_href(&entity))? {
+                self.report.skip(entity_href(&entity), "black list");
+            } else {
+                entities.push(entity);
+            }
+        }
+
+        let (file_count, bytes) = entities
+            .iter()
+            .filter_map(|entity| match entity {
+                ListEntity::File(file) => Some(file.content_length.max(0) as u64),
+                ListEntity::Folder(_) => None,
+            })
+            .fold((0, 0), |(count, total), bytes| (count + 1, total + bytes));
+        let mut progress = SyncProgress::new(self.progress, file_count, bytes);
+
+        let mut pending = entities.into_iter();
+        while let Some(entity) = pending.next() {
+            if self.stop_requested() {
+                info!("stopping sync before the next transfer");
+                for entity in std::iter::once(entity).chain(pending) {
+                    self.report.skip(entity_href(&entity), "sync stopped");
                 }
-                ListEntity::Folder(folder) => {
-                    if self.is_in_black_list(&folder.href)? {
-                        continue;
-                    }
+                break;
+            }
 
-                    self.create_dir(&folder, remote_dir).await?;
+            let result = match &entity {
+                ListEntity::File(file) => {
+                    self.download_file(file, remote_dir, props.get(&file.href), &mut progress)
+                        .await
+                }
+                ListEntity::Folder(folder) => {
+                    self.create_dir(folder, remote_dir, props.get(&folder.href))
+                        .await
                 }
+            };
+
+            // the next entries are still synced, the failures end the sync with an error
+            if let Err(err) = result {
+                error!(%err, href = entity_href(&entity), "error syncing entry");
+                self.report.fail(entity_href(&entity), &err.to_string());
             }
         }
+        progress.finish();
 
         Ok(())
     }
 
-    async fn create_dir(&mut self, folder: &ListFolder, remote_dir: &str) -> AppResult<()> {
+    #[instrument(name = "dir", skip_all, fields(href = %folder.href))]
+    async fn create_dir(
+        &mut self,
+        folder: &ListFolder,
+        remote_dir: &str,
+        props: Option<&OcProps>,
+    ) -> AppResult<()> {
         let base_url = Url::parse(format!("{}{}", self.config.host, remote_dir).as_str())?;
         let url_path = base_url.path();
         let remote_dir_path = &folder.href[url_path.len()..];
@@ -120,20 +773,27 @@ impl SyncService {
         }
 
         let remote_dir_path = urlencoding::decode(remote_dir_path)?;
-        println!("dir: {}", remote_dir_path);
+        debug!(path = %remote_dir_path, "creating folder");
 
         let path = self.config.out_dir.clone().join(remote_dir_path.as_ref());
 
         DirBuilder::new().create(&path).await?;
+        self.report
+            .created
+            .push(ReportEntry::new(&folder.href).with_path(&path));
 
-        self.local_version.add(
+        self.track(
             folder.href.clone(),
             LocalFile {
                 path,
                 is_dir: true,
                 last_modified: None,
+                local_modified: None,
+                size: None,
+                checksum: None,
+                file_id: props.and_then(|props| props.file_id.clone()),
             },
-        );
+        )?;
 
         Ok(())
     }
@@ -167,42 +827,253 @@ impl SyncService {
         })
     }
 
-    async fn download_file(&mut self, file: &ListFile, remote_dir: &str) -> AppResult<()> {
+    #[instrument(name = "file", skip_all, fields(href = %file.href))]
+    async fn download_file(
+        &mut self,
+        file: &ListFile,
+        remote_dir: &str,
+        props: Option<&OcProps>,
+        progress: &mut SyncProgress,
+    ) -> AppResult<()> {
         let download_uri = &file.href[self.config.host.path().len()..];
-        let dowloaded = DEFAULT_CONN_RETRY
-            .execute_with_retries(|| self.client.get(download_uri))
-            .await?
-            .bytes()
-            .await?;
-        //let dowloaded = self.client.get(download_uri).await?.bytes().await?;
-
         let paths = self.define_paths(remote_dir, &file.href)?;
-        println!("downloading: {}...", paths.remote.display());
+        info!(path = %paths.remote.display(), "downloading...");
+        if let Some(local) = self.local_version.get(&file.href) {
+            // only the conflict detection is skipped if the local file can't be checked
+            match local.verify(false) {
+                Ok(Some(reason @ (Tampering::Size | Tampering::ModifiedTime))) => {
+                    warn!(path = %local.path.display(), %reason, "overwriting local change");
+                    self.report
+                        .conflict(&file.href, &local.path, &reason.to_string());
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(%err, path = %local.path.display(), "can't check for local changes")
+                }
+            }
+        }
+        progress.start_file(
+            &paths.remote.to_string_lossy(),
+            file.content_length.max(0) as u64,
+        );
+
+        let part = PartFile::new(&paths.local);
+        let part_path = part.path.as_path();
+        let mut tries = CHECKSUM_TRIES;
+        let checksum = loop {
+            let (digest, header_checksum) =
+                self.download_to(download_uri, part_path, progress).await?;
+            let expected = header_checksum.or_else(|| props.and_then(OcProps::checksum).cloned());
+            match expected {
+                Some(expected) if !digest.matches(&expected) => {
+                    tries -= 1;
+                    if tries == 0 {
+                        return Err(format!(
+                            "checksum mismatch downloading {}",
+                            paths.remote.display()
+                        )
+                        .into());
+                    }
+
+                    warn!(tries_left = tries, "checksum mismatch, retrying...");
+                    progress.restart_file();
+                }
+                Some(expected) => break expected,
+                None => break digest.checksum(ChecksumAlgorithm::Sha1),
+            }
+        };
 
-        let mut local_file = File::create(&paths.local)?;
-        local_file.write_all(&dowloaded)?;
+        let local_file = File::options().write(true).open(part_path)?;
+        local_file.set_modified(file.last_modified.into())?;
+        drop(local_file);
 
-        self.local_version.add(
+        if let (Some(versioning), true) = (&self.config.versioning, paths.local.is_file()) {
+            Archive::new(&self.config.out_dir, versioning.clone()).archive(&paths.local)?;
+        }
+
+        part.persist(&paths.local)?;
+        let metadata = std::fs::metadata(&paths.local)?;
+
+        self.track(
             file.href.clone(),
             LocalFile {
-                path: paths.local,
+                path: paths.local.clone(),
                 is_dir: false,
                 last_modified: Some(file.last_modified),
+                local_modified: Some(metadata.modified()?.into()),
+                size: Some(metadata.len()),
+                checksum: Some(checksum.to_string()),
+                file_id: props.and_then(|props| props.file_id.clone()),
             },
+        )?;
+        self.report.downloaded.push(
+            ReportEntry::new(&file.href)
+                .with_path(&paths.local)
+                .with_bytes(metadata.len()),
         );
+        self.run_file_hook(Hook::FileDownloaded, &file.href, &paths.local)
+            .await;
+        self.emit(EventKind::FileDownloaded {
+            href: file.href.clone(),
+            path: paths.local,
+        });
+        progress.finish_file();
 
         Ok(())
     }
 
-    pub fn clear_out_dir(out_dir: &PathBuf) -> AppResult<()> {
-        let db_file_location = out_dir.join(".sync");
-        if !db_file_location.is_file() {
-            return Ok(());
+    /// Download `uri` into `path`, returning the content digest and the checksum sent by the server.
+    async fn download_to(
+        &mut self,
+        uri: &str,
+        path: &Path,
+        progress: &mut SyncProgress,
+    ) -> AppResult<(ContentDigest, Option<Checksum>)> {
+        let mut response = DEFAULT_CONN_RETRY
+            .execute_with_retries(|| METRICS.get_latency.time(self.client.get(uri)))
+            .await?;
+        let header_checksum = response
+            .headers()
+            .get(CHECKSUM_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Checksum::parse_list(value).into_iter().next());
+
+        let mut local_file = File::create(path)?;
+        let mut hasher = ContentHasher::new();
+        while let Some(chunk) = response.chunk().await? {
+            self.bandwidth.acquire(chunk.len()).await;
+            hasher.update(&chunk);
+            local_file.write_all(&chunk)?;
+            progress.advance(chunk.len());
+            self.report.bytes_transferred += chunk.len() as u64;
+        }
+
+        Ok((hasher.finish(), header_checksum))
+    }
+
+    /// Remove the synced entries of `out_dir` if its local db exists in `db_location`.
+    pub fn clear_out_dir(
+        out_dir: &Path,
+        db_location: &Path,
+        options: ClearOptions,
+    ) -> AppResult<()> {
+        // checked before locking, so no lock file is left behind for a missing db
+        let location = store::existing_location(db_location, out_dir);
+        let not_found = || {
+            format!(
+                "no local db found in {} for {}",
+                location.display(),
+                out_dir.display()
+            )
+        };
+        if !location.exists() {
+            return Err(not_found().into());
+        }
+
+        // the dry run only reads the db, the others lock it where the legacy one is moved to
+        let lock = match options.dry_run {
+            true => None,
+            false => Some(RunLock::acquire(db_location, options.wait)?),
+        };
+        let store = if options.dry_run {
+            store::open_existing(&location)?
+        } else {
+            store::relocate_legacy(db_location, out_dir)?;
+            store::open_existing(db_location)?
+        }
+        .ok_or_else(not_found)?;
+
+        if options.all {
+            Self::clear_all(out_dir, options.dry_run, lock.as_ref())?;
+            if !options.dry_run && store.location().exists() {
+                store::remove(store)?;
+            }
+
+            return Self::remove_lock(lock, out_dir);
+        }
+
+        let local_version = store.load()?;
+        let out_dir = std::path::absolute(out_dir)?;
+        let (mut folders, files): (Vec<_>, Vec<_>) = local_version
+            .iter()
+            .map(|(_, file)| file)
+            .filter(|file| {
+                let inside = is_inside(&out_dir, &file.path);
+                if !inside {
+                    warn!(path = %file.path.display(), "skipping entry outside the out dir");
+                }
+                inside
+            })
+            .partition(|file| file.is_dir);
+
+        for file in files {
+            if !file.path.is_file() {
+                continue;
+            }
+
+            if options.dry_run {
+                println!("would delete file: {}", file.path.display());
+                continue;
+            }
+
+            info!(path = %file.path.display(), "deleting file");
+            std::fs::remove_file(&file.path)?;
+        }
+
+        // children before parents, so the parents can become empty
+        folders.sort_by_key(|folder| std::cmp::Reverse(folder.path.components().count()));
+        for folder in folders {
+            if !folder.path.is_dir() {
+                continue;
+            }
+
+            if options.dry_run {
+                println!("would delete folder if empty: {}", folder.path.display());
+                continue;
+            }
+
+            if std::fs::read_dir(&folder.path)?.next().is_some() {
+                info!(path = %folder.path.display(), "keeping non empty folder");
+                continue;
+            }
+
+            info!(path = %folder.path.display(), "deleting folder");
+            std::fs::remove_dir(&folder.path)?;
+        }
+
+        if !options.dry_run {
+            store::remove(store)?;
+            store::remove_moved_marker(&out_dir)?;
+        }
+
+        Self::remove_lock(lock, &out_dir)
+    }
+
+    /// Release the `lock` of a removed db, removing its file if it is inside `out_dir`,
+    /// where the dbs of older versions are stored.
+    fn remove_lock(lock: Option<RunLock>, out_dir: &Path) -> AppResult<()> {
+        match lock {
+            Some(lock) if is_inside(&std::path::absolute(out_dir)?, lock.path()) => lock.remove(),
+            _ => Ok(()),
         }
+    }
 
+    /// Remove every entry of `out_dir`, synced or not, except the file of the `lock` held.
+    fn clear_all(out_dir: &Path, dry_run: bool, lock: Option<&RunLock>) -> AppResult<()> {
         let files = std::fs::read_dir(out_dir)?;
         for file in files {
             let path = file?.path();
+            if lock.is_some_and(|lock| {
+                std::path::absolute(lock.path()).ok() == std::path::absolute(&path).ok()
+            }) {
+                continue;
+            }
+
+            if dry_run {
+                println!("would delete: {}", path.display());
+                continue;
+            }
+
             if path.is_dir() {
                 std::fs::remove_dir_all(&path)?;
             } else {
@@ -213,8 +1084,285 @@ impl SyncService {
     }
 }
 
+fn entity_href(entity: &ListEntity) -> &Href {
+    match entity {
+        ListEntity::File(file) => &file.href,
+        ListEntity::Folder(folder) => &folder.href,
+    }
+}
+
+/// Temporal file where a download is written and verified before replacing `<name>`.
+/// It is removed when dropped, so failed or cancelled downloads don't leave it behind.
+struct PartFile {
+    path: PathBuf,
+}
+
+impl PartFile {
+    fn new(target: &Path) -> Self {
+        let mut file_name = target.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".nubesync-part");
+
+        Self {
+            path: target.with_file_name(file_name),
+        }
+    }
+
+    /// Move the downloaded file to `target`.
+    fn persist(self, target: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.path, target)
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                warn!(%err, path = %self.path.display(), "error removing partial download");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Set to `true` to stop the running syncs gracefully.
+pub type Shutdown = tokio::sync::watch::Receiver<bool>;
+
+/// Wait until `shutdown` is set, forever without it.
+/// Whether `path` is inside `dir`, an absolute path, resolving `path` from the current directory
+/// like `dir` was. Paths going up with `..` are never inside.
+fn is_inside(dir: &Path, path: &Path) -> bool {
+    if path
+        .components()
+        .any(|component| component == std::path::Component::ParentDir)
+    {
+        return false;
+    }
+
+    std::path::absolute(path).is_ok_and(|path| path.starts_with(dir) && path != dir)
+}
+
+async fn wait_shutdown(shutdown: Option<&mut Shutdown>) {
+    match shutdown {
+        Some(shutdown) => {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SyncOptions {
+    /// Check the local files before syncing.
+    pub verify: Option<VerifyMode>,
+    /// Skip the mass deletion limits.
+    pub force_delete: bool,
+    /// Sync even if the local db belongs to another host or remote location.
+    pub rebind: bool,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct WatchOptions {
+    /// Time between syncs.
+    pub interval: Duration,
+    /// Max time between syncs while they keep failing, the delay doubles after each failure.
+    pub max_backoff: Duration,
+    /// Also sync when the server `notify_push` app reports changes.
+    pub push: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ClearOptions {
+    /// Remove every entry of the out dir, not only the synced ones.
+    pub all: bool,
+    /// Only print what would be removed.
+    pub dry_run: bool,
+    /// Wait for other runs using the db instead of failing.
+    pub wait: bool,
+}
+
+/// How deep the local files are checked before syncing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Existence, size and modification time.
+    Metadata,
+    /// Metadata plus the content checksum.
+    Content,
+}
+
 #[derive(Debug, Clone)]
 pub struct DavPaths {
     pub remote: PathBuf,
     pub local: PathBuf,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: &str = "remote.php/dav/files/user/docs/";
+    const BASE: &str = "/remote.php/dav/files/user/docs/";
+
+    async fn service(dir: &Path) -> SyncService {
+        service_with_db(dir, &dir.join("db")).await
+    }
+
+    async fn service_with_db(dir: &Path, db_location: &Path) -> SyncService {
+        let config = format!(
+            r#"
+            host = "http://127.0.0.1/"
+            username = "user"
+            password = "secret"
+            out_dir = "{}"
+            black_list = []
+            "#,
+            dir.join("out").display()
+        );
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+
+        SyncService::init(toml::from_str(&config).unwrap(), db_location, false)
+            .await
+            .unwrap()
+    }
+
+    fn href(path: &str) -> Href {
+        format!("{}{}", BASE, path)
+    }
+
+    fn local_file(path: PathBuf, is_dir: bool) -> LocalFile {
+        LocalFile {
+            path,
+            is_dir,
+            last_modified: None,
+            local_modified: None,
+            size: None,
+            checksum: None,
+            file_id: None,
+        }
+    }
+
+    fn moved(from: &str, to: &str) -> Move {
+        Move {
+            from: href(from),
+            to: href(to),
+        }
+    }
+
+    #[tokio::test]
+    async fn folder_renamed_and_child_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mut sync = service(dir.path()).await;
+        std::fs::create_dir_all(out.join("a")).unwrap();
+        std::fs::write(out.join("a/kept.txt"), "kept").unwrap();
+        std::fs::write(out.join("a/gone.txt"), "gone").unwrap();
+        sync.track(href("a/"), local_file(out.join("a"), true))
+            .unwrap();
+        for name in ["kept.txt", "gone.txt"] {
+            let file = local_file(out.join("a").join(name), false);
+            sync.track(href(&format!("a/{}", name)), file).unwrap();
+        }
+
+        let moves = [moved("a/", "b/"), moved("a/kept.txt", "b/kept.txt")];
+        let skipped = sync.apply_moves(REMOTE, &moves).unwrap();
+        assert!(skipped.is_empty(), "{:?}", skipped);
+        sync.delete_locals(vec![href("a/gone.txt")]).await.unwrap();
+
+        assert!(!out.join("a").exists());
+        assert_eq!(
+            std::fs::read_to_string(out.join("b/kept.txt")).unwrap(),
+            "kept"
+        );
+        assert!(!out.join("b/gone.txt").exists());
+        assert_eq!(
+            sync.local_version.get(&href("b/kept.txt")).unwrap().path,
+            out.join("b/kept.txt")
+        );
+        assert!(sync.local_version.get(&href("a/gone.txt")).is_none());
+        assert!(sync.local_version.get(&href("a/kept.txt")).is_none());
+    }
+
+    #[test]
+    fn entries_inside_out_dir() {
+        let out = Path::new("/srv/out");
+        assert!(is_inside(out, Path::new("/srv/out/a/b.txt")));
+        assert!(!is_inside(out, Path::new("/srv/out")));
+        assert!(!is_inside(out, Path::new("/srv/other/b.txt")));
+        assert!(!is_inside(out, Path::new("/srv/out/../other/b.txt")));
+        assert!(!is_inside(out, Path::new("/srv/outside/b.txt")));
+    }
+
+    #[tokio::test]
+    async fn clear_keeps_entries_outside_out_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let other = dir.path().join("other.txt");
+        let mut sync = service(dir.path()).await;
+        std::fs::write(out.join("synced.txt"), "synced").unwrap();
+        std::fs::write(&other, "other").unwrap();
+        sync.track(
+            href("synced.txt"),
+            local_file(out.join("synced.txt"), false),
+        )
+        .unwrap();
+        sync.track(href("other.txt"), local_file(other.clone(), false))
+            .unwrap();
+        sync.store.flush().unwrap();
+        drop(sync);
+
+        SyncService::clear_out_dir(&out, &dir.path().join("db"), ClearOptions::default()).unwrap();
+        assert!(!out.join("synced.txt").exists());
+        assert!(other.exists());
+    }
+
+    #[tokio::test]
+    async fn clear_db_in_out_dir_leaves_no_lock() {
+        for all in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let out = dir.path().join("out");
+            let db_location = out.join(".sync");
+            let mut sync = service_with_db(dir.path(), &db_location).await;
+            std::fs::write(out.join("synced.txt"), "synced").unwrap();
+            sync.track(
+                href("synced.txt"),
+                local_file(out.join("synced.txt"), false),
+            )
+            .unwrap();
+            sync.store.flush().unwrap();
+            drop(sync);
+
+            let options = ClearOptions {
+                all,
+                ..Default::default()
+            };
+            SyncService::clear_out_dir(&out, &db_location, options).unwrap();
+            let left: Vec<_> = std::fs::read_dir(&out).unwrap().collect();
+            assert!(left.is_empty(), "{:?}", left);
+        }
+    }
+
+    #[test]
+    fn clear_missing_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_location = dir.path().join("state/nubesync/db");
+
+        let err = SyncService::clear_out_dir(dir.path(), &db_location, ClearOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("no local db found"), "{}", err);
+        assert!(!dir.path().join("state").exists());
+    }
+
+    #[tokio::test]
+    async fn unrelated_file_in_move_target_is_not_adopted() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mut sync = service(dir.path()).await;
+        std::fs::write(out.join("new.txt"), "unrelated").unwrap();
+        sync.track(href("old.txt"), local_file(out.join("old.txt"), false))
+            .unwrap();
+
+        let moves = [moved("old.txt", "new.txt")];
+        let skipped = sync.apply_moves(REMOTE, &moves).unwrap();
+        assert_eq!(skipped, moves);
+        assert!(sync.local_version.get(&href("new.txt")).is_none());
+    }
+}
@@ -0,0 +1,40 @@
+use std::{os::unix::net::UnixDatagram, time::Duration};
+
+use crate::result::AppResult;
+
+/// Send a state change, like `READY=1`, to the service manager.
+/// Does nothing when not started by systemd with `Type=notify`.
+pub fn notify(state: &str) -> AppResult<()> {
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    let socket_path = socket_path.to_string_lossy();
+    match socket_path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), socket_path.as_ref())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Time between watchdog pings, half of the timeout set with `WatchdogSec`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
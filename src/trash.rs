@@ -0,0 +1,352 @@
+use std::{
+    fs::{DirBuilder, File},
+    io::{ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use chrono::{Duration, Local, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::result::AppResult;
+
+/// Folder inside the out dir where the deleted entries are moved.
+pub const TRASH_DIR: &str = ".nubesync-trash";
+
+const BATCH_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+/// What to do with local entries removed on the server.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionMode {
+    /// Erase them.
+    #[default]
+    Delete,
+    /// Move them to `.nubesync-trash/<timestamp>/` inside the out dir.
+    Trash,
+    /// Move them to the freedesktop trash of the user.
+    SystemTrash,
+}
+
+/// Local trash of an out dir. Every sync moves its deletions to a new batch folder.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    out_dir: PathBuf,
+    root: PathBuf,
+}
+
+/// Entry found inside a trash batch.
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    pub batch: String,
+    pub path: PathBuf,
+}
+
+impl Trash {
+    pub fn new(out_dir: &Path) -> Self {
+        Self {
+            out_dir: out_dir.to_path_buf(),
+            root: out_dir.join(TRASH_DIR),
+        }
+    }
+
+    /// Name of a new batch for the current time.
+    pub fn new_batch() -> String {
+        Utc::now().format(BATCH_FORMAT).to_string()
+    }
+
+    /// Move `path`, which must be inside the out dir, to the `batch` folder.
+    pub fn move_to_trash(&self, batch: &str, path: &Path) -> AppResult<()> {
+        let relative = path.strip_prefix(&self.out_dir)?;
+        let destination = self.root.join(batch).join(relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::rename(path, destination)?;
+
+        Ok(())
+    }
+
+    /// Files and empty folders of every batch, oldest batch first.
+    pub fn list(&self) -> AppResult<Vec<TrashEntry>> {
+        let mut entries = Vec::new();
+        for batch in self.batches()? {
+            let mut paths = Vec::new();
+            collect_files(&self.root.join(&batch), &mut paths)?;
+            for path in paths {
+                let relative = path.strip_prefix(self.root.join(&batch))?.to_path_buf();
+                entries.push(TrashEntry {
+                    batch: batch.clone(),
+                    path: relative,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Move back to the out dir a whole batch, or only `path` inside it.
+    /// Restored entries are not tracked again, since they no longer exist in the server:
+    /// later syncs leave them alone, and only `clear --all` removes them.
+    pub fn restore(&self, batch: &str, path: Option<&Path>) -> AppResult<()> {
+        let batch_dir = self.root.join(batch);
+        if !batch_dir.is_dir() {
+            return Err(format!("trash batch not found: {}", batch).into());
+        }
+
+        let mut to_restore = Vec::new();
+        match path {
+            Some(path) => to_restore.push(batch_dir.join(path)),
+            None => collect_files(&batch_dir, &mut to_restore)?,
+        }
+
+        for source in to_restore {
+            let relative = source.strip_prefix(&batch_dir)?;
+            let destination = self.out_dir.join(relative);
+            if destination.exists() {
+                warn!(path = %destination.display(), "skipping, already exists");
+                continue;
+            }
+
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            info!(path = %destination.display(), "restoring");
+            std::fs::rename(&source, &destination)?;
+        }
+
+        remove_empty_dirs(&batch_dir)?;
+
+        Ok(())
+    }
+
+    /// Erase the batches older than `max_age`, or every batch if not set.
+    pub fn purge(&self, max_age: Option<Duration>) -> AppResult<()> {
+        let limit = max_age.map(|max_age| Utc::now().naive_utc() - max_age);
+        for batch in self.batches()? {
+            let is_expired = match (limit, NaiveDateTime::parse_from_str(&batch, BATCH_FORMAT)) {
+                (Some(limit), Ok(created)) => created < limit,
+                (Some(_), Err(_)) => false,
+                (None, _) => true,
+            };
+
+            if is_expired {
+                info!(batch, "purging trash batch");
+                std::fs::remove_dir_all(self.root.join(&batch))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn batches(&self) -> AppResult<Vec<String>> {
+        if !self.root.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                batches.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+        batches.sort();
+
+        Ok(batches)
+    }
+}
+
+/// Move `path` to the freedesktop trash. That is `$XDG_DATA_HOME/Trash` when both are in the
+/// same filesystem, or `.Trash-<uid>` in the mount point of `path` otherwise, so entries are
+/// never copied across filesystems.
+pub fn move_to_system_trash(path: &Path) -> AppResult<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("invalid path to trash: {}", path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let absolute_path = std::fs::canonicalize(parent)?.join(&file_name);
+    let device = std::fs::symlink_metadata(&absolute_path)?.dev();
+
+    let home_trash = home_trash()?;
+    let (trash_dir, info_path) = if existing_device(&home_trash)? == device {
+        (home_trash, absolute_path.clone())
+    } else {
+        let top_dir = mount_point(&absolute_path, device)?;
+        let uid = unsafe { libc::getuid() };
+        let trash_dir = top_dir.join(format!(".Trash-{}", uid));
+        // trash dirs in mount points use paths relative to them
+        let info_path = absolute_path.strip_prefix(&top_dir)?.to_path_buf();
+        (trash_dir, info_path)
+    };
+
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    let mut builder = DirBuilder::new();
+    builder.recursive(true).mode(0o700);
+    builder.create(&files_dir)?;
+    builder.create(&info_dir)?;
+
+    let mut trash_name = file_name.clone();
+    let mut counter = 1;
+    while files_dir.join(&trash_name).exists() || info_dir.join(info_name(&trash_name)).exists() {
+        counter += 1;
+        trash_name = format!("{}.{}", file_name, counter);
+    }
+
+    let mut info = File::create(info_dir.join(info_name(&trash_name)))?;
+    write!(
+        info,
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        urlencoding::encode(&info_path.to_string_lossy()).replace("%2F", "/"),
+        Local::now().format("%Y-%m-%dT%H:%M:%S")
+    )?;
+
+    std::fs::rename(path, files_dir.join(trash_name))?;
+
+    Ok(())
+}
+
+/// Trash of the user, in `$XDG_DATA_HOME/Trash`.
+fn home_trash() -> AppResult<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .ok_or("cannot find the user trash, neither XDG_DATA_HOME nor HOME are set")?;
+
+    Ok(data_home.join("Trash"))
+}
+
+/// Device of `path` or of its nearest existing parent.
+fn existing_device(path: &Path) -> AppResult<u64> {
+    for ancestor in path.ancestors() {
+        match std::fs::metadata(ancestor) {
+            Ok(metadata) => return Ok(metadata.dev()),
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err(format!("cannot find the filesystem of {}", path.display()).into())
+}
+
+/// Top folder of the filesystem `device` containing the absolute `path`.
+fn mount_point(path: &Path, device: u64) -> AppResult<PathBuf> {
+    let mut top_dir = path;
+    for ancestor in path.ancestors().skip(1) {
+        if std::fs::metadata(ancestor)?.dev() != device {
+            break;
+        }
+        top_dir = ancestor;
+    }
+
+    Ok(top_dir.to_path_buf())
+}
+
+fn info_name(trash_name: &str) -> String {
+    format!("{}.trashinfo", trash_name)
+}
+
+/// Collect the files and the empty folders inside `dir` recursively.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> AppResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && std::fs::read_dir(&path)?.next().is_some() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove `dir` and its sub folders if they do not contain files.
+fn remove_empty_dirs(dir: &Path) -> AppResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+        }
+    }
+
+    if std::fs::read_dir(dir)?.next().is_none() {
+        std::fs::remove_dir(dir)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch_at(age: Duration) -> String {
+        (Utc::now() - age).format(BATCH_FORMAT).to_string()
+    }
+
+    #[test]
+    fn move_and_restore() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let out = out_dir.path();
+        std::fs::create_dir_all(out.join("dir")).unwrap();
+        std::fs::write(out.join("dir/a.txt"), "a").unwrap();
+        std::fs::write(out.join("b.txt"), "b").unwrap();
+
+        let trash = Trash::new(out);
+        let batch = Trash::new_batch();
+        trash.move_to_trash(&batch, &out.join("dir/a.txt")).unwrap();
+        trash.move_to_trash(&batch, &out.join("b.txt")).unwrap();
+        assert!(!out.join("dir/a.txt").exists());
+
+        let mut listed: Vec<_> = trash.list().unwrap().into_iter().map(|e| e.path).collect();
+        listed.sort();
+        assert_eq!(listed, [PathBuf::from("b.txt"), PathBuf::from("dir/a.txt")]);
+
+        // entries in the way are not overwritten
+        std::fs::write(out.join("b.txt"), "new").unwrap();
+        trash.restore(&batch, None).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("dir/a.txt")).unwrap(), "a");
+        assert_eq!(std::fs::read_to_string(out.join("b.txt")).unwrap(), "new");
+        assert_eq!(trash.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn purge_expired_batches() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let trash = Trash::new(out_dir.path());
+        let old = batch_at(Duration::days(10));
+        let recent = batch_at(Duration::days(1));
+        for batch in [&old, &recent, &"manual".to_string()] {
+            std::fs::create_dir_all(out_dir.path().join(TRASH_DIR).join(batch)).unwrap();
+        }
+
+        trash.purge(Some(Duration::days(5))).unwrap();
+        assert_eq!(trash.batches().unwrap(), [recent, "manual".to_string()]);
+
+        trash.purge(None).unwrap();
+        assert!(trash.batches().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mount_point_of_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = std::fs::canonicalize(dir.path()).unwrap();
+        let device = std::fs::metadata(&path).unwrap().dev();
+
+        let top_dir = mount_point(&path, device).unwrap();
+        assert!(path.starts_with(&top_dir));
+        assert_eq!(std::fs::metadata(&top_dir).unwrap().dev(), device);
+        if let Some(parent) = top_dir.parent() {
+            assert_ne!(std::fs::metadata(parent).unwrap().dev(), device);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    fmt::Display,
     fs::File,
     io::{Read, Write},
     path::{Path, PathBuf},
@@ -12,7 +13,12 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "version_migration")]
 use named_ctor::NamedCtor;
 
-use crate::result::AppResult;
+use crate::{
+    checksum::{file_digest, Checksum},
+    dav::OcProps,
+    migrations::{self, SCHEMA_VERSION},
+    result::AppResult,
+};
 
 #[derive(Debug, Clone)]
 pub struct VersionService {
@@ -21,8 +27,12 @@ pub struct VersionService {
 }
 
 impl VersionService {
-    pub fn init(local: LocalVersion, entities: Vec<ListEntity>) -> Self {
-        let server_version = ServerVersion::from_entities(&entities);
+    pub fn init(
+        local: LocalVersion,
+        entities: Vec<ListEntity>,
+        props: &HashMap<Href, OcProps>,
+    ) -> Self {
+        let server_version = ServerVersion::from_entities(&entities, props);
         let version = Version::new(&server_version, &local);
 
         Self { version, entities }
@@ -48,6 +58,11 @@ impl VersionService {
     pub fn version(&self) -> &Version {
         &self.version
     }
+
+    /// Every entity listed in the server.
+    pub fn entities(&self) -> &[ListEntity] {
+        &self.entities
+    }
 }
 
 pub type Href = String;
@@ -57,36 +72,151 @@ pub struct LocalFile {
     pub path: PathBuf,
     pub is_dir: bool,
     pub last_modified: Option<DateTime<Utc>>,
+    /// Modification time of the local file right after it was written.
+    #[serde(default)]
+    pub local_modified: Option<DateTime<Utc>>,
+    /// Size in bytes of the downloaded file.
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Checksum of the downloaded content, like `SHA1:<hex>`.
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// Nextcloud id of the entry, stable across renames and moves.
+    #[serde(default)]
+    pub file_id: Option<String>,
+}
+
+impl LocalFile {
+    /// Check if the local file mtime no longer matches the one recorded after the download.
+    pub fn modified_on_disk(&self) -> bool {
+        let Some(local_modified) = self.local_modified else {
+            return false;
+        };
+
+        match std::fs::metadata(&self.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => DateTime::<Utc>::from(modified) != local_modified,
+            Err(_) => false,
+        }
+    }
+
+    /// Compare the local entry against the recorded metadata, and optionally its content.
+    pub fn verify(&self, check_content: bool) -> AppResult<Option<Tampering>> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Some(Tampering::Missing))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if self.is_dir {
+            return Ok((!metadata.is_dir()).then_some(Tampering::Missing));
+        }
+
+        if !metadata.is_file() {
+            return Ok(Some(Tampering::Missing));
+        }
+
+        if self.size.is_some_and(|size| size != metadata.len()) {
+            return Ok(Some(Tampering::Size));
+        }
+
+        if self.modified_on_disk() {
+            return Ok(Some(Tampering::ModifiedTime));
+        }
+
+        if let (true, Some(checksum)) = (check_content, &self.checksum) {
+            let expected: Checksum = checksum.parse()?;
+            if !file_digest(&self.path)?.matches(&expected) {
+                return Ok(Some(Tampering::Content));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Kind of local change found in a synced entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tampering {
+    Missing,
+    Size,
+    ModifiedTime,
+    Content,
+}
+
+impl Display for Tampering {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Tampering::Missing => "missing",
+            Tampering::Size => "size changed",
+            Tampering::ModifiedTime => "modification time changed",
+            Tampering::Content => "content changed",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+/// Server and remote location a local db is synced with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub host: String,
+    pub remote_dir: String,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} in {}", self.remote_dir, self.host)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[cfg_attr(feature = "version_migration", derive(NamedCtor))]
 pub struct LocalVersion {
+    schema_version: u32,
+    /// Unknown in dbs written before it was recorded, until the next sync.
+    #[serde(default)]
+    origin: Option<Origin>,
     files: HashMap<Href, LocalFile>,
 }
 
+impl Default for LocalVersion {
+    fn default() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            origin: None,
+            files: HashMap::new(),
+        }
+    }
+}
+
 impl LocalVersion {
-    /// Search file named `.sync` in `parent_dir` to get the last version of files.
-    pub fn load_from_file(parent_dir: PathBuf) -> AppResult<Self> {
-        let file = File::open(parent_dir.join(".sync"));
+    /// Read the JSON file in `location` to get the last version of files.
+    /// Older formats are migrated and saved again.
+    pub fn load_from_file(location: &Path) -> AppResult<Self> {
+        let file = File::open(location);
         if let Err(err) = &file {
             if err.kind() == std::io::ErrorKind::NotFound {
-                return Ok(LocalVersion {
-                    files: HashMap::new(),
-                });
+                return Ok(LocalVersion::default());
             }
         }
 
         let mut file_content = String::new();
-        let _ = file.unwrap().read_to_string(&mut file_content)?;
-        let last_version = serde_json::from_str(&file_content)?;
+        let _ = file?.read_to_string(&mut file_content)?;
+        let db = serde_json::from_str(&file_content)?;
+
+        let (db, migrated) = migrations::migrate_json(location, db)?;
+        let last_version: LocalVersion = serde_json::from_value(db)?;
+        if migrated {
+            last_version.save_in_file(location)?;
+        }
 
         Ok(last_version)
     }
 
-    pub fn save_in_file(&self, parent_dir: &Path) -> AppResult<()> {
-        let path = parent_dir.join(".sync");
-        let mut file = File::create(path)?;
+    pub fn save_in_file(&self, location: &Path) -> AppResult<()> {
+        let mut file = File::create(location)?;
         let json_version = serde_json::to_string_pretty(&self)?;
 
         file.write_all(json_version.as_bytes())?;
@@ -94,6 +224,14 @@ impl LocalVersion {
         Ok(())
     }
 
+    pub fn origin(&self) -> Option<&Origin> {
+        self.origin.as_ref()
+    }
+
+    pub fn set_origin(&mut self, origin: Option<Origin>) {
+        self.origin = origin;
+    }
+
     pub fn add(&mut self, href: Href, file: LocalFile) {
         self.files.insert(href, file);
     }
@@ -101,6 +239,18 @@ impl LocalVersion {
     pub fn remove(&mut self, href: &Href) -> Option<LocalFile> {
         self.files.remove(href)
     }
+
+    pub fn get(&self, href: &Href) -> Option<&LocalFile> {
+        self.files.get(href)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Href, &LocalFile)> {
+        self.files.iter()
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -114,27 +264,44 @@ pub enum Status {
 #[derive(Debug, Clone)]
 pub struct ServerVersion<'a> {
     pub files: HashMap<&'a Href, &'a ListEntity>,
+    pub file_ids: HashMap<&'a str, &'a Href>,
 }
 
 impl<'a> ServerVersion<'a> {
-    pub fn from_entities(server_files: &'a [ListEntity]) -> ServerVersion<'a> {
+    pub fn from_entities(
+        server_files: &'a [ListEntity],
+        props: &'a HashMap<Href, OcProps>,
+    ) -> ServerVersion<'a> {
         let mut files = HashMap::new();
+        let mut file_ids = HashMap::new();
         for f in server_files {
             let href = match f {
                 ListEntity::File(file) => &file.href,
                 ListEntity::Folder(folder) => &folder.href,
             };
 
+            if let Some(file_id) = props.get(href).and_then(|props| props.file_id.as_deref()) {
+                file_ids.insert(file_id, href);
+            }
+
             files.insert(href, f);
         }
 
-        ServerVersion { files }
+        ServerVersion { files, file_ids }
     }
 }
 
+/// Entry renamed or moved on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub from: Href,
+    pub to: Href,
+}
+
 #[derive(Debug, Clone)]
 pub struct Version {
     paths: HashMap<Href, Status>,
+    moves: Vec<Move>,
 }
 
 impl Version {
@@ -148,7 +315,10 @@ impl Version {
             match paths.get_mut(*href) {
                 Some(status) => {
                     if let ListEntity::File(file) = server_file {
-                        if file.last_modified != local.files[*href].last_modified.unwrap() {
+                        let local_file = &local.files[*href];
+                        if Some(file.last_modified) != local_file.last_modified
+                            || local_file.modified_on_disk()
+                        {
                             *status = Status::OutOfDate;
                             continue;
                         }
@@ -162,7 +332,58 @@ impl Version {
             }
         }
 
-        Version { paths }
+        let moves = Self::find_moves(server, local, &mut paths);
+
+        Version { paths, moves }
+    }
+
+    /// Match local entries missing on the server with new server entries sharing the same file id.
+    fn find_moves(
+        server: &ServerVersion,
+        local: &LocalVersion,
+        paths: &mut HashMap<Href, Status>,
+    ) -> Vec<Move> {
+        let mut moves = Vec::new();
+        for (href, local_file) in &local.files {
+            if paths[href] != Status::Local {
+                continue;
+            }
+
+            let Some(file_id) = &local_file.file_id else {
+                continue;
+            };
+
+            let Some(new_href) = server.file_ids.get(file_id.as_str()) else {
+                continue;
+            };
+
+            if paths.get(*new_href) != Some(&Status::Server) {
+                continue;
+            }
+
+            let status = match server.files[*new_href] {
+                ListEntity::File(file) if Some(file.last_modified) != local_file.last_modified => {
+                    Status::OutOfDate
+                }
+                _ => Status::Sync,
+            };
+
+            paths.remove(href);
+            paths.insert((*new_href).clone(), status);
+            moves.push(Move {
+                from: href.clone(),
+                to: (*new_href).clone(),
+            });
+        }
+
+        moves.sort_by_key(|m| m.from.len());
+
+        moves
+    }
+
+    /// Entries to rename locally, parents before children.
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
     }
 
     pub fn files_to_remove(&self) -> Vec<Href> {
@@ -187,3 +408,146 @@ impl Version {
         paths
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use reqwest_dav::list_cmd::ListFile;
+
+    use super::*;
+
+    fn modified(day: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, day, 0, 0, 0).unwrap()
+    }
+
+    fn server_file(href: &str, day: u32) -> ListEntity {
+        ListEntity::File(ListFile {
+            href: href.to_string(),
+            last_modified: modified(day),
+            content_length: 1,
+            content_type: "text/plain".to_string(),
+            tag: None,
+        })
+    }
+
+    fn local_file(path: &str, day: u32, file_id: Option<&str>) -> LocalFile {
+        LocalFile {
+            path: PathBuf::from(path),
+            is_dir: false,
+            last_modified: Some(modified(day)),
+            local_modified: None,
+            size: None,
+            checksum: None,
+            file_id: file_id.map(str::to_string),
+        }
+    }
+
+    fn file_ids(ids: &[(&str, &str)]) -> HashMap<Href, OcProps> {
+        ids.iter()
+            .map(|(href, file_id)| {
+                let props = OcProps {
+                    file_id: Some(file_id.to_string()),
+                    ..Default::default()
+                };
+                (href.to_string(), props)
+            })
+            .collect()
+    }
+
+    fn version(
+        local: &[(&str, LocalFile)],
+        server: &[ListEntity],
+        props: &HashMap<Href, OcProps>,
+    ) -> Version {
+        let mut local_version = LocalVersion::default();
+        for (href, file) in local {
+            local_version.add(href.to_string(), file.clone());
+        }
+
+        Version::new(&ServerVersion::from_entities(server, props), &local_version)
+    }
+
+    #[test]
+    fn rename_is_a_move() {
+        let local = [("/dav/a.txt", local_file("out/a.txt", 1, Some("7")))];
+        let server = [server_file("/dav/b.txt", 1)];
+        let version = version(&local, &server, &file_ids(&[("/dav/b.txt", "7")]));
+
+        assert_eq!(
+            version.moves(),
+            [Move {
+                from: "/dav/a.txt".to_string(),
+                to: "/dav/b.txt".to_string()
+            }]
+        );
+        assert!(version.files_to_remove().is_empty());
+        assert!(version.files_to_download().is_empty());
+    }
+
+    #[test]
+    fn moved_and_modified_is_downloaded() {
+        let local = [("/dav/a.txt", local_file("out/a.txt", 1, Some("7")))];
+        let server = [server_file("/dav/b.txt", 2)];
+        let version = version(&local, &server, &file_ids(&[("/dav/b.txt", "7")]));
+
+        assert_eq!(version.moves().len(), 1);
+        assert_eq!(version.files_to_download(), ["/dav/b.txt"]);
+    }
+
+    #[test]
+    fn entries_without_file_id_are_deleted_and_downloaded() {
+        let local = [("/dav/a.txt", local_file("out/a.txt", 1, None))];
+        let server = [server_file("/dav/b.txt", 1)];
+        let version = version(&local, &server, &file_ids(&[("/dav/b.txt", "7")]));
+
+        assert!(version.moves().is_empty());
+        assert_eq!(version.files_to_remove(), ["/dav/a.txt"]);
+        assert!(version
+            .files_to_download()
+            .contains(&"/dav/b.txt".to_string()));
+    }
+
+    #[test]
+    fn kept_entries_are_not_moves() {
+        let local = [
+            ("/dav/a.txt", local_file("out/a.txt", 1, Some("7"))),
+            ("/dav/b.txt", local_file("out/b.txt", 1, Some("8"))),
+        ];
+        // the id moved to an entry already tracked
+        let server = [server_file("/dav/a.txt", 1), server_file("/dav/b.txt", 1)];
+        let version = version(
+            &local,
+            &server,
+            &file_ids(&[("/dav/a.txt", "8"), ("/dav/b.txt", "7")]),
+        );
+
+        assert!(version.moves().is_empty());
+        assert!(version.files_to_remove().is_empty());
+    }
+
+    #[test]
+    fn parents_move_first() {
+        let local = [
+            (
+                "/dav/dir/sub/c.txt",
+                local_file("out/dir/sub/c.txt", 1, Some("3")),
+            ),
+            ("/dav/dir/", local_file("out/dir", 1, Some("1"))),
+            ("/dav/dir/sub/", local_file("out/dir/sub", 1, Some("2"))),
+        ];
+        let server = [
+            server_file("/dav/new/", 1),
+            server_file("/dav/new/sub/", 1),
+            server_file("/dav/new/sub/c.txt", 1),
+        ];
+        let props = file_ids(&[
+            ("/dav/new/", "1"),
+            ("/dav/new/sub/", "2"),
+            ("/dav/new/sub/c.txt", "3"),
+        ]);
+        let version = version(&local, &server, &props);
+
+        let from: Vec<_> = version.moves().iter().map(|m| m.from.as_str()).collect();
+        assert_eq!(from, ["/dav/dir/", "/dav/dir/sub/", "/dav/dir/sub/c.txt"]);
+    }
+}